@@ -9,6 +9,14 @@ fn test_cdb() -> CDB {
     CDB::open("tests/test2.cdb").expect("Could not open tests/test2.cdb")
 }
 
+fn test_keys() -> Vec<Vec<u8>> {
+    // Every tenth key from tests/test2.sh, plus some missing keys
+    (1..=100u64)
+        .map(|i| (i * 10 * 3141592654 % 1000000).to_string().into_bytes())
+        .chain((1..=20u64).map(|i| format!("missing{}", i).into_bytes()))
+        .collect()
+}
+
 fn reader_benchmark(c: &mut Criterion) {
     c.bench_function("CDB::open", |b| {
         b.iter(|| {
@@ -43,6 +51,27 @@ fn reader_benchmark(c: &mut Criterion) {
             }
         })
     });
+    c.bench_function("CDB::get loop", |b| {
+        let cdb = test_cdb();
+        let keys = test_keys();
+        b.iter(|| {
+            for key in &keys {
+                if let Some(result) = cdb.get(key) {
+                    result.unwrap();
+                }
+            }
+        })
+    });
+    c.bench_function("CDB::get_many", |b| {
+        let cdb = test_cdb();
+        let keys = test_keys();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        b.iter(|| {
+            for result in cdb.get_many(&keys).into_iter().flatten() {
+                result.unwrap();
+            }
+        })
+    });
     c.bench_function("CDB::iter result loop", |b| {
         let cdb = test_cdb();
         b.iter(|| {
@@ -61,5 +90,48 @@ fn reader_benchmark(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, reader_benchmark);
+fn load_factor_benchmark(c: &mut Criterion) {
+    let keys: Vec<Vec<u8>> = (0..10000u32)
+        .map(|i| format!("key{}", i).into_bytes())
+        .collect();
+    let missing: Vec<Vec<u8>> = (0..10000u32)
+        .map(|i| format!("missing{}", i).into_bytes())
+        .collect();
+    for &ratio in &[1.25, 1.5, 2.0, 3.0, 4.0] {
+        let filename = format!("target/bench-load-factor-{}.cdb", ratio);
+        let mut cdb = cdb::CDBWriter::create(&filename).unwrap();
+        cdb.set_slots_per_entry(ratio).unwrap();
+        for key in &keys {
+            cdb.add(key, b"value").unwrap();
+        }
+        let (cdb, _) = cdb.finish_and_open().unwrap();
+        std::fs::remove_file(&filename).unwrap();
+        let cdb = std::rc::Rc::new(cdb);
+        let hits = cdb.clone();
+        let hit_keys = keys.clone();
+        c.bench_function(
+            &format!("CDB::get hits, {} slots per entry", ratio),
+            move |b| {
+                b.iter(|| {
+                    for key in hit_keys.iter().step_by(100) {
+                        hits.get(key).unwrap().unwrap();
+                    }
+                })
+            },
+        );
+        let misses = missing.clone();
+        c.bench_function(
+            &format!("CDB::get misses, {} slots per entry", ratio),
+            move |b| {
+                b.iter(|| {
+                    for key in misses.iter().step_by(100) {
+                        assert!(cdb.get(key).is_none());
+                    }
+                })
+            },
+        );
+    }
+}
+
+criterion_group!(benches, reader_benchmark, load_factor_benchmark);
 criterion_main!(benches);
@@ -0,0 +1,80 @@
+//! A Bloom filter over CDB key hashes, stored in the file trailer, so
+//! lookups of absent keys can usually skip probing the hash tables.
+//!
+//! The filter is encoded as the number of hash functions and the number
+//! of bits, followed by the bits themselves. Bit positions are derived
+//! from the standard CDB hash of the key by double hashing.
+
+use std::ops::Range;
+
+use crate::hash::mix64;
+use crate::uint32;
+
+/// The most bits per key a filter may use. Beyond this the false
+/// positive rate is already negligible, and larger values only make
+/// the filter huge.
+pub(crate) const MAX_BITS_PER_KEY: u32 = 64;
+
+/// Spread a 32 bit key hash into the two hashes used for double
+/// hashing, the second of which is odd.
+fn hashes(khash: u32) -> (u64, u64) {
+    let h = mix64(khash as u64);
+    (h & 0xffffffff, (h >> 32) | 1)
+}
+
+/// Build the encoded filter for a set of key hashes, using
+/// approximately `bits_per_key` bits for each.
+pub(crate) fn build(khashes: &[u32], bits_per_key: u32) -> Vec<u8> {
+    let nbits = (khashes.len() as u64 * bits_per_key as u64).clamp(64, 0xffffffff / 8 * 8);
+    let nbits = nbits.div_ceil(8) * 8;
+    // The optimal number of hash functions is ln(2) bits per key.
+    let k = ((bits_per_key as f64 * 0.69).round() as u32).clamp(1, 30);
+    let mut buf = vec![0; 8 + (nbits / 8) as usize];
+    uint32::pack2(&mut buf[..8], k, nbits as u32);
+    let bits = &mut buf[8..];
+    for &khash in khashes {
+        let (h1, h2) = hashes(khash);
+        for i in 0..k as u64 {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % nbits;
+            bits[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+    buf
+}
+
+/// A filter within a mapped file.
+pub(crate) struct Bloom {
+    k: u32,
+    nbits: u64,
+    bits: Range<usize>,
+}
+
+impl Bloom {
+    /// Locate a filter from its section of the file, returning `None`
+    /// if it is malformed.
+    pub(crate) fn parse(map: &[u8], section: Range<usize>) -> Option<Bloom> {
+        if section.len() < 8 {
+            return None;
+        }
+        let (k, nbits) = uint32::unpack2(&map[section.start..section.start + 8]);
+        let bits = section.start + 8..section.end;
+        if k == 0 || nbits == 0 || nbits as u64 > bits.len() as u64 * 8 {
+            return None;
+        }
+        Some(Bloom {
+            k,
+            nbits: nbits as u64,
+            bits,
+        })
+    }
+
+    /// Returns `false` if no key with this hash was added to the filter.
+    pub(crate) fn may_contain(&self, map: &[u8], khash: u32) -> bool {
+        let bits = &map[self.bits.clone()];
+        let (h1, h2) = hashes(khash);
+        (0..self.k as u64).all(|i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.nbits;
+            bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0
+        })
+    }
+}
@@ -0,0 +1,270 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub use std::io::Result;
+
+/// The filesystem operations used by
+/// [`CDBWriter`](struct.CDBWriter.html) to publish a finished file.
+///
+/// The default methods perform the real operations, so an
+/// implementation need only override the ones it is interested in,
+/// for example to observe or inject failures in tests.
+pub(crate) trait Filesystem: Send {
+    /// Flush the contents of a file to stable storage.
+    fn sync_file(&self, file: &fs::File) -> Result<()> {
+        file.sync_all()
+    }
+
+    /// Rename a file, replacing any existing destination.
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        fs::rename(from, to)
+    }
+
+    /// Flush a directory to stable storage, making renames within it
+    /// durable.
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        sync_dir(dir)
+    }
+
+    /// Remove a file.
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        fs::remove_file(path)
+    }
+
+    /// Create a new hard link to a file, failing with `AlreadyExists`
+    /// rather than replacing an existing destination.
+    #[cfg(unix)]
+    fn hard_link(&self, from: &Path, to: &Path) -> Result<()> {
+        fs::hard_link(from, to)
+    }
+
+    /// Copy the permissions, ownership and extended attributes of an
+    /// existing file onto an open file. Ownership and attributes which
+    /// cannot be set without privileges are skipped.
+    fn copy_attributes(&self, from: &Path, to: &fs::File) -> Result<()> {
+        copy_attributes(from, to)
+    }
+
+    /// Rename a file within an open directory, replacing any existing
+    /// destination. Relative paths are resolved from the directory.
+    #[cfg(unix)]
+    fn rename_at(&self, dir: &fs::File, from: &Path, to: &Path) -> Result<()> {
+        rename_at(dir, from, to)
+    }
+
+    /// Flush an open directory to stable storage, making renames within
+    /// it durable.
+    #[cfg(unix)]
+    fn sync_dir_handle(&self, dir: &fs::File) -> Result<()> {
+        dir.sync_all()
+    }
+}
+
+/// The operating system's filesystem.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct OsFilesystem;
+
+impl Filesystem for OsFilesystem {}
+
+/// Open a lock file and take an exclusive advisory lock on it,
+/// failing with `WouldBlock` if another process holds the lock. The
+/// lock is released when the returned file is closed.
+#[cfg(unix)]
+pub(crate) fn lock_file(path: &Path) -> Result<fs::File> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn lock_file(_path: &Path) -> Result<fs::File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Locking is not supported on this platform",
+    ))
+}
+
+/// Convert a path to a C string for passing to a system call.
+#[cfg(unix)]
+fn c_path(path: &Path) -> Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(std::ffi::CString::new(path.as_os_str().as_bytes())?)
+}
+
+/// Check the result of a system call returning -1 on error.
+#[cfg(unix)]
+fn cvt(result: libc::c_int) -> Result<libc::c_int> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+/// Create a new file for reading and writing within an open
+/// directory, failing if it already exists.
+#[cfg(unix)]
+pub(crate) fn create_new_at(dir: &fs::File, path: &Path) -> Result<fs::File> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let path = c_path(path)?;
+    let fd = cvt(unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            path.as_ptr(),
+            libc::O_RDWR | libc::O_CREAT | libc::O_EXCL | libc::O_CLOEXEC,
+            0o666 as libc::c_uint,
+        )
+    })?;
+    Ok(unsafe { fs::File::from_raw_fd(fd) })
+}
+
+#[cfg(unix)]
+fn rename_at(dir: &fs::File, from: &Path, to: &Path) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let (from, to) = (c_path(from)?, c_path(to)?);
+    let fd = dir.as_raw_fd();
+    cvt(unsafe { libc::renameat(fd, from.as_ptr(), fd, to.as_ptr()) })?;
+    Ok(())
+}
+
+/// Remove a file within an open directory.
+#[cfg(unix)]
+pub(crate) fn remove_at(dir: &fs::File, path: &Path) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let path = c_path(path)?;
+    cvt(unsafe { libc::unlinkat(dir.as_raw_fd(), path.as_ptr(), 0) })?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    // Directories cannot be opened for syncing on this platform.
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_attributes(from: &Path, to: &fs::File) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    let meta = fs::metadata(from)?;
+    // Change ownership first, as doing so may clear the set-user-ID
+    // and set-group-ID bits of the mode.
+    if unsafe { libc::fchown(to.as_raw_fd(), meta.uid(), meta.gid()) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EPERM) {
+            return Err(err);
+        }
+        // An unprivileged owner may still set the group, if they are a
+        // member of it.
+        unsafe { libc::fchown(to.as_raw_fd(), !0, meta.gid()) };
+    }
+    to.set_permissions(meta.permissions())?;
+    copy_xattrs(from, to)
+}
+
+#[cfg(not(unix))]
+fn copy_attributes(from: &Path, to: &fs::File) -> Result<()> {
+    to.set_permissions(fs::metadata(from)?.permissions())
+}
+
+#[cfg(target_os = "linux")]
+fn copy_xattrs(from: &Path, to: &fs::File) -> Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+
+    let path = CString::new(from.as_os_str().as_bytes())?;
+    let names = match read_xattr(|buf, len| unsafe {
+        libc::listxattr(path.as_ptr(), buf as *mut libc::c_char, len)
+    }) {
+        Ok(names) => names,
+        Err(ref err) if err.raw_os_error() == Some(libc::ENOTSUP) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    for name in names.split(|&c| c == 0).filter(|name| !name.is_empty()) {
+        let name = CString::new(name)?;
+        let value = match read_xattr(|buf, len| unsafe {
+            libc::getxattr(path.as_ptr(), name.as_ptr(), buf as *mut libc::c_void, len)
+        }) {
+            Ok(value) => value,
+            Err(ref err) if is_unprivileged(err) => continue,
+            Err(err) => return Err(err),
+        };
+        let result = unsafe {
+            libc::fsetxattr(
+                to.as_raw_fd(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        if result != 0 {
+            let err = io::Error::last_os_error();
+            if !is_unprivileged(&err) {
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check whether an extended attribute could not be read or set for
+/// lack of privileges, or because the filesystem does not support it.
+/// Namespaces such as `trusted` and `security` are refused with
+/// `EPERM` or `EACCES` depending on the filesystem and security module.
+#[cfg(target_os = "linux")]
+fn is_unprivileged(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EPERM) | Some(libc::EACCES) | Some(libc::ENOTSUP)
+    )
+}
+
+/// Read a variable sized extended attribute value or list, retrying
+/// if it grows between querying the size and reading it.
+#[cfg(target_os = "linux")]
+fn read_xattr<F>(read: F) -> Result<Vec<u8>>
+where
+    F: Fn(*mut u8, usize) -> isize,
+{
+    loop {
+        let size = read(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; size as usize];
+        let size = read(buf.as_mut_ptr(), buf.len());
+        if size >= 0 {
+            buf.truncate(size as usize);
+            return Ok(buf);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn copy_xattrs(_from: &Path, _to: &fs::File) -> Result<()> {
+    Ok(())
+}
@@ -0,0 +1,190 @@
+//! Generational publishing, where each finished file is kept under a
+//! numbered name and the destination is a symbolic link to the current
+//! generation.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use crate::filesystem::Filesystem;
+use crate::writer::random;
+
+pub use std::io::Result;
+
+fn err_nogeneration<T>() -> Result<T> {
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "No earlier generation to roll back to",
+    ))
+}
+
+/// Split the destination into its directory and file name.
+fn split(dst: &Path) -> Result<(&Path, &OsStr)> {
+    let name = dst
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid file name"))?;
+    let dir = match dst.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    Ok((dir, name))
+}
+
+/// The name of generation `n`, as `NAME.N`.
+fn generation_name(name: &OsStr, n: u64) -> OsString {
+    let mut result = name.to_os_string();
+    result.push(format!(".{}", n));
+    result
+}
+
+/// Parse the generation number from a file name of the form `NAME.N`.
+fn parse_generation(name: &OsStr, file: &OsStr) -> Option<u64> {
+    let suffix = file.as_bytes().strip_prefix(name.as_bytes())?;
+    let digits = suffix.strip_prefix(b".")?;
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+/// List the existing generations of the destination, oldest first.
+fn list(dst: &Path) -> Result<Vec<u64>> {
+    let (dir, name) = split(dst)?;
+    let mut generations = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        if let Some(n) = parse_generation(name, &entry?.file_name()) {
+            generations.push(n);
+        }
+    }
+    generations.sort_unstable();
+    Ok(generations)
+}
+
+/// The generation the destination currently links to, if any.
+fn current(dst: &Path) -> Result<Option<u64>> {
+    let (_, name) = split(dst)?;
+    match fs::read_link(dst) {
+        Ok(target) => Ok(target
+            .file_name()
+            .and_then(|file| parse_generation(name, file))),
+        Err(ref err)
+            if err.kind() == io::ErrorKind::NotFound
+                || err.kind() == io::ErrorKind::InvalidInput =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Atomically replace the destination with a link to generation `n`.
+fn swap_link(filesystem: &dyn Filesystem, dst: &Path, n: u64) -> Result<()> {
+    let (dir, name) = split(dst)?;
+    let target = generation_name(name, n);
+    loop {
+        let mut link = name.to_os_string();
+        link.push(format!(".link.{:016x}", random()));
+        let link = dir.join(link);
+        match symlink(&target, &link) {
+            Ok(()) => {
+                return filesystem.rename(&link, dst).map_err(|err| {
+                    let _ = filesystem.remove_file(&link);
+                    err
+                })
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Link a file as the first free generation from `n` onwards, returning
+/// the generation it took. Linking never replaces an existing name, so
+/// concurrent publishers cannot claim the same generation.
+fn link_generation(
+    filesystem: &dyn Filesystem,
+    from: &Path,
+    dst: &Path,
+    mut n: u64,
+) -> Result<u64> {
+    let (dir, name) = split(dst)?;
+    loop {
+        match filesystem.hard_link(from, &dir.join(generation_name(name, n))) {
+            Ok(()) => return Ok(n),
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => n += 1,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Check whether the destination is a regular file rather than a link
+/// to a generation.
+fn is_regular_file(dst: &Path) -> Result<bool> {
+    match fs::symlink_metadata(dst) {
+        Ok(meta) => Ok(meta.file_type().is_file()),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Remove all but the newest `keep` generations.
+fn prune(filesystem: &dyn Filesystem, dst: &Path, keep: usize) -> Result<()> {
+    let (dir, name) = split(dst)?;
+    let generations = list(dst)?;
+    let prune = generations.len().saturating_sub(keep);
+    for &n in &generations[..prune] {
+        filesystem.remove_file(&dir.join(generation_name(name, n)))?;
+    }
+    Ok(())
+}
+
+/// Move a finished temporary file into place as the next generation,
+/// point the destination at it, and remove all but the newest `keep`
+/// generations.
+///
+/// A regular file at the destination, as published without
+/// generations, is first kept as the generation before the new one so
+/// that it can be rolled back to. Pruning only tidies up, so a failure
+/// to prune is ignored once the destination points at the new
+/// generation; the old generations are pruned again on the next
+/// publish.
+pub(crate) fn publish(
+    filesystem: &dyn Filesystem,
+    tmpname: &Path,
+    dst: &Path,
+    keep: usize,
+) -> Result<()> {
+    let last = list(dst)?.last().copied();
+    let mut next = last.map_or(1, |n| n + 1);
+    if is_regular_file(dst)? {
+        next = link_generation(filesystem, dst, dst, last.map_or(0, |n| n + 1))? + 1;
+    }
+    let n = link_generation(filesystem, tmpname, dst, next)?;
+    filesystem.remove_file(tmpname)?;
+    swap_link(filesystem, dst, n)?;
+    let _ = prune(filesystem, dst, keep);
+    Ok(())
+}
+
+/// Point the destination back at the generation before the current
+/// one, returning the path of that generation. The directory is synced
+/// afterwards if `sync_dir` is set.
+pub(crate) fn rollback(filesystem: &dyn Filesystem, dst: &Path, sync_dir: bool) -> Result<PathBuf> {
+    let (dir, name) = split(dst)?;
+    let current = match current(dst)? {
+        Some(current) => current,
+        None => return err_nogeneration(),
+    };
+    let previous = match list(dst)?.into_iter().rev().find(|&n| n < current) {
+        Some(previous) => previous,
+        None => return err_nogeneration(),
+    };
+    swap_link(filesystem, dst, previous)?;
+    if sync_dir {
+        filesystem.sync_dir(dir)?;
+    }
+    Ok(dir.join(generation_name(name, previous)))
+}
@@ -13,6 +13,27 @@ pub fn hash(buf: &[u8]) -> u32 {
     h
 }
 
+/// The 64 bit finalizer from MurmurHash3, which mixes every input bit
+/// into every output bit.
+pub(crate) fn mix64(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^= h >> 33;
+    h
+}
+
+/// A 64 bit hash of a key, independent of the standard CDB hash, used
+/// by the perfect hash index. This is FNV-1a followed by `mix64`.
+pub(crate) fn hash64(buf: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for c in buf {
+        h = (h ^ *c as u64).wrapping_mul(0x100000001b3);
+    }
+    mix64(h)
+}
+
 #[test]
 fn samples() {
     assert_eq!(hash(b""), 0x0001505);
@@ -36,12 +36,30 @@
 //!  * [Constant Database (cdb) Internals](https://www.unixuser.org/~euske/doc/cdbinternals/index.html)
 //!  * [Wikipedia](https://en.wikipedia.org/wiki/Cdb_(software))
 
-extern crate filebuffer;
-
+mod bloom;
+mod casefold;
+mod filesystem;
+#[cfg(unix)]
+mod generations;
 mod hash;
+mod mmap;
+mod normalize;
+#[cfg(feature = "rayon")]
+mod parallel;
+mod perfect;
 mod reader;
+mod sort;
+mod trailer;
 mod uint32;
 mod writer;
 
-pub use crate::reader::{CDBIter, CDBKeyValueIter, CDBValueIter, Result, CDB};
-pub use crate::writer::{CDBMake, CDBWriter};
+pub use crate::mmap::Advice;
+pub use crate::normalize::{AsciiLowercase, Normalizer, UnicodeCasefold};
+#[cfg(feature = "rayon")]
+pub use crate::parallel::{CDBParallelMake, CDBStage};
+pub use crate::reader::{
+    CDBDistinctKeyIter, CDBGroupIter, CDBIter, CDBKeyIter, CDBKeyValueIter,
+    CDBKeyValueLocationIter, CDBLocationIter, CDBRangeIter, CDBValueIter, CDBValueReader,
+    CDBValueReaderIter, Cursor, HashedKey, MemoryLock, OpenOptions, RecordLocation, Result, CDB,
+};
+pub use crate::writer::{BuildObserver, BuildStats, CDBMake, CDBWriter, DuplicateKey, Duplicates};
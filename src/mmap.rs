@@ -0,0 +1,105 @@
+//! Read-only memory map of an open file.
+
+use std::fs;
+use std::io;
+use std::ops::{Deref, Range};
+
+pub use std::io::Result;
+
+/// Advice to the kernel about how a [`CDB`](struct.CDB.html) file will
+/// be accessed, which controls how much it reads ahead.
+///
+/// Advice has no effect on platforms other than Unix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Advice {
+    /// No special treatment.
+    Normal,
+    /// Pages will be accessed in random order, as by lookups, so
+    /// reading ahead is of little use.
+    Random,
+    /// Pages will be accessed in order, as by
+    /// [`CDB::iter`](struct.CDB.html#method.iter), so they can be read
+    /// ahead aggressively.
+    Sequential,
+    /// The whole file will be accessed soon, so it should be read in
+    /// the background.
+    WillNeed,
+}
+
+/// The contents of a file, mapped into memory.
+pub struct Mmap {
+    map: memmap2::Mmap,
+}
+
+impl Mmap {
+    /// Map the entire contents of the file. The file must not be empty.
+    pub fn map(file: &fs::File) -> Result<Mmap> {
+        let len = file.metadata()?.len();
+        if len == 0 || len > usize::MAX as u64 {
+            return Err(io::Error::other("File cannot be mapped"));
+        }
+        // The map is undefined if the file is truncated or modified
+        // while it is mapped, as with any mapped reader. CDB files are
+        // replaced by renaming a new file into place rather than being
+        // modified, and the writer only maps records it has finished
+        // writing.
+        let map = unsafe { memmap2::Mmap::map(file)? };
+        Ok(Mmap { map })
+    }
+
+    /// Advise the kernel how the whole map will be accessed.
+    #[cfg(unix)]
+    pub fn advise(&self, advice: Advice) -> Result<()> {
+        self.map.advise(match advice {
+            Advice::Normal => memmap2::Advice::Normal,
+            Advice::Random => memmap2::Advice::Random,
+            Advice::Sequential => memmap2::Advice::Sequential,
+            Advice::WillNeed => memmap2::Advice::WillNeed,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn advise(&self, _advice: Advice) -> Result<()> {
+        Ok(())
+    }
+
+    /// Lock a range of the map into memory, until it is unmapped.
+    #[cfg(unix)]
+    pub fn lock(&self, range: Range<usize>) -> Result<()> {
+        // The start of the range must be aligned to a page.
+        let start = range.start / page_size() * page_size();
+        let range = &self.map[start..range.end];
+        // The pages stay locked until they are unmapped, when the map is
+        // dropped, not just while the slice is borrowed.
+        if unsafe { libc::mlock(range.as_ptr() as *const libc::c_void, range.len()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn lock(&self, _range: Range<usize>) -> Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Locking memory is not supported on this platform",
+        ))
+    }
+}
+
+/// The size of a page of memory.
+#[cfg(unix)]
+pub fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[cfg(not(unix))]
+pub fn page_size() -> usize {
+    4096
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.map
+    }
+}
@@ -0,0 +1,121 @@
+//! Key normalization, for databases where different spellings of a key,
+//! such as different letter cases, should be treated as the same key.
+
+use std::borrow::Cow;
+use std::str;
+use std::sync::Arc;
+
+use crate::casefold;
+
+/// A normalization applied to keys before they are hashed or compared.
+///
+/// A normalizer is set when writing a file with
+/// [`CDBMake::set_normalizer`](struct.CDBMake.html#method.set_normalizer)
+/// and its name is recorded in the file. Keys are stored as they were
+/// added, but lookups find every key with the same normalized form.
+/// Files using a built-in normalizer are opened with it automatically,
+/// while others must be opened with
+/// [`CDB::open_with_normalizer`](struct.CDB.html#method.open_with_normalizer).
+pub trait Normalizer: Send + Sync {
+    /// A name which identifies this normalization, and which must
+    /// change whenever its results change.
+    fn name(&self) -> &str;
+
+    /// Normalize a key.
+    fn normalize<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]>;
+}
+
+/// Converts ASCII letters to lower case, leaving all other bytes alone.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AsciiLowercase;
+
+impl Normalizer for AsciiLowercase {
+    fn name(&self) -> &str {
+        "ascii-lowercase"
+    }
+
+    fn normalize<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> {
+        if key.iter().any(u8::is_ascii_uppercase) {
+            Cow::Owned(key.to_ascii_lowercase())
+        } else {
+            Cow::Borrowed(key)
+        }
+    }
+}
+
+/// Folds the case of keys encoded as UTF-8, so that keys which differ
+/// only in case are the same.
+///
+/// This is Unicode full case folding, using the common and full
+/// mappings of `CaseFolding.txt` from Unicode 14.0.0. For example,
+/// `"Straße"`, `"STRASSE"` and `"strasse"` all fold to `"strasse"`, and
+/// `"ΣΑΣ"` and `"σας"` both fold to `"σασ"`. Keys are not otherwise
+/// normalized, so a precomposed character and its decomposed form
+/// remain different. Bytes which are not valid UTF-8 are left alone.
+///
+/// The folding table is built into the crate rather than taken from
+/// the standard library, so its results do not depend on the version
+/// of Rust, and files written with it can always be opened.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UnicodeCasefold;
+
+impl UnicodeCasefold {
+    /// Append the folded form of a valid UTF-8 string.
+    fn fold(valid: &str, folded: &mut Vec<u8>) {
+        let mut buf = [0; 4];
+        for c in valid.chars() {
+            match casefold::FOLDS.binary_search_by_key(&c, |&(from, _)| from) {
+                Ok(i) => folded.extend_from_slice(casefold::FOLDS[i].1.as_bytes()),
+                Err(_) => folded.extend_from_slice(c.encode_utf8(&mut buf).as_bytes()),
+            }
+        }
+    }
+}
+
+impl Normalizer for UnicodeCasefold {
+    fn name(&self) -> &str {
+        "unicode-casefold-14.0.0"
+    }
+
+    fn normalize<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> {
+        if key.iter().all(|c| c.is_ascii() && !c.is_ascii_uppercase()) {
+            return Cow::Borrowed(key);
+        }
+        let mut folded = Vec::with_capacity(key.len());
+        let mut rest = key;
+        loop {
+            match str::from_utf8(rest) {
+                Ok(valid) => {
+                    UnicodeCasefold::fold(valid, &mut folded);
+                    return Cow::Owned(folded);
+                }
+                Err(err) => {
+                    let (valid, invalid) = rest.split_at(err.valid_up_to());
+                    UnicodeCasefold::fold(str::from_utf8(valid).unwrap(), &mut folded);
+                    let len = err.error_len().unwrap_or(invalid.len());
+                    folded.extend_from_slice(&invalid[..len]);
+                    rest = &invalid[len..];
+                }
+            }
+        }
+    }
+}
+
+/// Look up a built-in normalizer by the name recorded in a file.
+pub(crate) fn builtin(name: &[u8]) -> Option<Arc<dyn Normalizer>> {
+    if name == AsciiLowercase.name().as_bytes() {
+        Some(Arc::new(AsciiLowercase))
+    } else if name == UnicodeCasefold.name().as_bytes() {
+        Some(Arc::new(UnicodeCasefold))
+    } else {
+        None
+    }
+}
+
+/// Normalize a key with an optional normalizer.
+pub(crate) fn apply<'a>(normalizer: &Option<Arc<dyn Normalizer>>, key: &'a [u8]) -> Cow<'a, [u8]> {
+    match normalizer {
+        Some(normalizer) => normalizer.normalize(key),
+        None => Cow::Borrowed(key),
+    }
+}
@@ -0,0 +1,275 @@
+use rayon::prelude::*;
+use std::fs;
+use std::io;
+use std::mem;
+use std::sync::Mutex;
+
+use crate::hash::hash;
+use crate::uint32;
+use crate::writer::{err_toobig, fill_table, HashPos};
+
+pub use std::io::Result;
+
+/// Staged records are written out once they reach this many bytes.
+const STAGE_SIZE: usize = 1 << 22;
+
+/// A block of records written by one stage, with its hash entries
+/// already sorted into header buckets. Entry positions are relative to
+/// the start of the block until it is placed in the file.
+struct Block {
+    pos: u32,
+    buckets: Vec<Vec<HashPos>>,
+}
+
+struct State {
+    pos: u32,
+    blocks: Vec<Block>,
+    error: Option<io::Error>,
+}
+
+/// Interface for making a CDB file from multiple threads.
+///
+/// Records are added through [`CDBStage`](struct.CDBStage.html)
+/// handles, one per thread, which hash and buffer records before
+/// writing them into the file in blocks. When all the stages are
+/// finished, the 256 hash tables are built in parallel. The result is
+/// a standard CDB file. Records added through a single stage produce
+/// exactly the same file as [`CDBMake`](struct.CDBMake.html).
+///
+/// None of the build options of `CDBMake` are available, and the file
+/// is always written as a plain CDB file:
+///
+/// - records are written in the order their blocks are flushed, never
+///   sorted as with [`CDBMake::sort`](struct.CDBMake.html#method.sort);
+/// - duplicate keys are all kept, as there is no
+///   [`unique_keys`](struct.CDBMake.html#method.unique_keys) mode;
+/// - hash tables always have two slots for each record, as there is no
+///   [`set_slots_per_entry`](struct.CDBMake.html#method.set_slots_per_entry);
+/// - no Bloom filter, perfect hash index or sorted key index is
+///   written, so lookups always probe the hash tables and
+///   [`CDB::prefix`](struct.CDB.html#method.prefix) and
+///   [`CDB::range`](struct.CDB.html#method.range) are unsupported;
+/// - keys are hashed exactly as given, as there is no
+///   [`set_normalizer`](struct.CDBMake.html#method.set_normalizer);
+/// - there is no build observer, and `finish` returns no statistics.
+///
+/// Use `CDBMake` for files which need any of these.
+///
+/// # Example
+///
+/// ```no_run
+/// fn main() -> std::io::Result<()> {
+///     let file = std::fs::File::create("temporary.cdb")?;
+///     let cdb = cdb::CDBParallelMake::new(file)?;
+///     std::thread::scope(|scope| {
+///         let threads: Vec<_> = (0..4)
+///             .map(|i| {
+///                 let cdb = &cdb;
+///                 scope.spawn(move || {
+///                     let mut stage = cdb.stage();
+///                     stage.add(format!("key{}", i).as_bytes(), b"value")?;
+///                     stage.finish()
+///                 })
+///             })
+///             .collect();
+///         threads.into_iter().try_for_each(|t| t.join().unwrap())
+///     })?;
+///     cdb.finish()?;
+///     Ok(())
+/// }
+/// ```
+pub struct CDBParallelMake {
+    file: fs::File,
+    state: Mutex<State>,
+}
+
+impl CDBParallelMake {
+    /// Create a new parallel CDB maker.
+    pub fn new(file: fs::File) -> Result<CDBParallelMake> {
+        Ok(CDBParallelMake {
+            file,
+            state: Mutex::new(State {
+                pos: 2048,
+                blocks: Vec::new(),
+                error: None,
+            }),
+        })
+    }
+
+    /// Create a new handle for adding records, typically one for each
+    /// thread.
+    pub fn stage(&self) -> CDBStage<'_> {
+        CDBStage {
+            make: self,
+            data: Vec::new(),
+            buckets: vec![vec![]; 256],
+        }
+    }
+
+    /// Reserve space for a block of records and write it into the file.
+    fn write_block(&self, data: &[u8], buckets: Vec<Vec<HashPos>>) -> Result<()> {
+        let pos = {
+            let mut state = self.state.lock().unwrap();
+            let pos = state.pos;
+            let end = pos as u64 + data.len() as u64;
+            if end > 0xffffffff {
+                return err_toobig();
+            }
+            state.pos = end as u32;
+            state.blocks.push(Block { pos, buckets });
+            pos
+        };
+        write_at(&self.file, data, pos)
+    }
+
+    /// Remember the first error from any stage, as the records it lost
+    /// make the file unusable.
+    fn poison(&self, err: &io::Error) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .error
+            .get_or_insert_with(|| io::Error::new(err.kind(), err.to_string()));
+    }
+
+    /// Finish writing to the CDB file.
+    ///
+    /// All stages must have been finished or dropped before this is
+    /// called, which the borrow checker enforces.
+    pub fn finish(self) -> Result<()> {
+        let CDBParallelMake { file, state } = self;
+        let state = state.into_inner().unwrap();
+        if let Some(err) = state.error {
+            return Err(err);
+        }
+        let blocks = state.blocks;
+
+        let tables: Vec<Vec<u8>> = (0..256)
+            .into_par_iter()
+            .map(|i| {
+                let mut entries: Vec<HashPos> = blocks
+                    .iter()
+                    .flat_map(|block| {
+                        block.buckets[i].iter().map(move |e| HashPos {
+                            hash: e.hash,
+                            pos: block.pos + e.pos,
+                        })
+                    })
+                    .collect();
+                // Keep records with the same key in the order they
+                // appear in the file.
+                entries.sort_unstable_by_key(|e| e.pos);
+                let mut table = vec![HashPos { hash: 0, pos: 0 }; entries.len() * 2];
+                fill_table(&entries, &mut table);
+                let mut buf = vec![0; table.len() * 8];
+                for (hp, slot) in table.iter().zip(buf.chunks_mut(8)) {
+                    hp.pack(slot);
+                }
+                buf
+            })
+            .collect();
+
+        let mut header = [0u8; 2048];
+        let mut positions = Vec::with_capacity(256);
+        let mut pos = state.pos;
+        for (i, table) in tables.iter().enumerate() {
+            let j = i * 8;
+            uint32::pack2(&mut header[j..j + 8], pos, (table.len() / 8) as u32);
+            positions.push(pos);
+            let end = pos as u64 + table.len() as u64;
+            if end > 0xffffffff {
+                return err_toobig();
+            }
+            pos = end as u32;
+        }
+
+        tables
+            .par_iter()
+            .zip(positions)
+            .try_for_each(|(table, pos)| write_at(&file, table, pos))?;
+        write_at(&file, &header, 0)
+    }
+}
+
+/// A handle for adding records to a
+/// [`CDBParallelMake`](struct.CDBParallelMake.html).
+///
+/// Records are hashed and buffered in the stage, then written into the
+/// file in large blocks. Records added through one stage appear in the
+/// file in the order they were added.
+pub struct CDBStage<'a> {
+    make: &'a CDBParallelMake,
+    data: Vec<u8>,
+    buckets: Vec<Vec<HashPos>>,
+}
+
+impl<'a> CDBStage<'a> {
+    /// Add a record to the CDB file.
+    pub fn add(&mut self, key: &[u8], data: &[u8]) -> Result<()> {
+        if key.len() >= 0xffffffff || data.len() >= 0xffffffff {
+            return Err(io::Error::other("Key or data too big"));
+        }
+        let khash = hash(key);
+        self.buckets[(khash & 0xff) as usize].push(HashPos {
+            hash: khash,
+            pos: self.data.len() as u32,
+        });
+        let mut buf = [0; 8];
+        uint32::pack2(&mut buf, key.len() as u32, data.len() as u32);
+        self.data.extend_from_slice(&buf);
+        self.data.extend_from_slice(key);
+        self.data.extend_from_slice(data);
+        if self.data.len() >= STAGE_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write any buffered records into the file.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.data.is_empty() {
+            return Ok(());
+        }
+        let buckets = mem::replace(&mut self.buckets, vec![vec![]; 256]);
+        let result = self.make.write_block(&self.data, buckets);
+        self.data.clear();
+        if let Err(err) = &result {
+            self.make.poison(err);
+        }
+        result
+    }
+
+    /// Finish adding records through this stage, writing out any
+    /// buffered records.
+    pub fn finish(mut self) -> Result<()> {
+        self.flush()
+    }
+}
+
+impl<'a> Drop for CDBStage<'a> {
+    fn drop(&mut self) {
+        // Any error is also reported by CDBParallelMake::finish
+        let _ = self.flush();
+    }
+}
+
+#[cfg(unix)]
+fn write_at(file: &fs::File, buf: &[u8], pos: u32) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buf, pos as u64)
+}
+
+#[cfg(windows)]
+fn write_at(file: &fs::File, mut buf: &[u8], pos: u32) -> Result<()> {
+    use std::os::windows::fs::FileExt;
+    let mut pos = pos as u64;
+    while !buf.is_empty() {
+        match file.seek_write(buf, pos)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => {
+                buf = &buf[n..];
+                pos += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
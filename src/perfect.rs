@@ -0,0 +1,133 @@
+//! A minimal perfect hash index, stored in the file trailer in place of
+//! the standard hash tables, for databases where every key is unique.
+//!
+//! The index is built with the "hash and displace" method. Keys are
+//! split by their 64 bit hash into buckets of a few keys each, and each
+//! bucket is given a displacement which places all of its keys into
+//! distinct free slots, largest buckets first. Each slot holds the
+//! position of one record, so a lookup reads exactly one slot.
+//!
+//! The index is encoded as the number of slots and buckets, followed by
+//! the displacement of each bucket and then the record position in each
+//! slot.
+
+use std::ops::Range;
+
+use crate::hash::mix64;
+use crate::uint32;
+
+/// The average number of keys in each bucket.
+const BUCKET_SIZE: usize = 4;
+
+fn bucket(khash: u64, buckets: u32) -> u32 {
+    (((khash >> 32) * buckets as u64) >> 32) as u32
+}
+
+fn slot(khash: u64, displacement: u32, slots: u32) -> u32 {
+    (mix64(khash ^ (displacement as u64).wrapping_mul(0x9e3779b97f4a7c15)) % slots as u64) as u32
+}
+
+/// Build the encoded index for a set of `(hash, position)` pairs with
+/// distinct hashes, or `None` if no displacement can be found for some
+/// bucket.
+pub(crate) fn build(mut keys: Vec<(u64, u32)>) -> Option<Vec<u8>> {
+    let n = keys.len() as u32;
+    let nb = keys.len().div_ceil(BUCKET_SIZE).max(1) as u32;
+    keys.sort_unstable_by_key(|&(khash, _)| bucket(khash, nb));
+
+    // The range of keys in each bucket, largest first.
+    let mut ranges = Vec::with_capacity(nb as usize);
+    let mut start = 0;
+    while start < keys.len() {
+        let b = bucket(keys[start].0, nb);
+        let end = start
+            + keys[start..]
+                .iter()
+                .take_while(|k| bucket(k.0, nb) == b)
+                .count();
+        ranges.push((b, start..end));
+        start = end;
+    }
+    ranges.sort_by_key(|(b, range)| (usize::MAX - range.len(), *b));
+
+    // Give up rather than search forever if the hashes are degenerate.
+    let limit = (n as u64 * 64).clamp(1 << 16, u32::MAX as u64) as u32;
+    let mut taken = vec![false; n as usize];
+    let mut displacements = vec![0u32; nb as usize];
+    let mut positions = vec![0u32; n as usize];
+    let mut slots = Vec::with_capacity(BUCKET_SIZE * 4);
+    for (b, range) in ranges {
+        let bucket_keys = &keys[range];
+        let displacement = (0..limit).find(|&d| {
+            slots.clear();
+            bucket_keys.iter().all(|&(khash, _)| {
+                let s = slot(khash, d, n);
+                let free = !taken[s as usize] && !slots.contains(&s);
+                slots.push(s);
+                free
+            })
+        })?;
+        displacements[b as usize] = displacement;
+        for (&s, &(_, pos)) in slots.iter().zip(bucket_keys) {
+            taken[s as usize] = true;
+            positions[s as usize] = pos;
+        }
+    }
+
+    let mut buf = vec![0; 8 + (nb as usize + n as usize) * 4];
+    uint32::pack2(&mut buf[..8], n, nb);
+    for (value, out) in displacements
+        .iter()
+        .chain(positions.iter())
+        .zip(buf[8..].chunks_mut(4))
+    {
+        uint32::pack(out, *value);
+    }
+    Some(buf)
+}
+
+/// An index within a mapped file.
+pub(crate) struct PerfectHash {
+    slots: u32,
+    buckets: u32,
+    start: usize,
+}
+
+impl PerfectHash {
+    /// Locate an index from its section of the file, returning `None`
+    /// if it is malformed.
+    pub(crate) fn parse(map: &[u8], section: Range<usize>) -> Option<PerfectHash> {
+        if section.len() < 8 {
+            return None;
+        }
+        let (slots, buckets) = uint32::unpack2(&map[section.start..section.start + 8]);
+        if slots == 0
+            || buckets == 0
+            || (section.len() as u64 - 8) != (slots as u64 + buckets as u64) * 4
+        {
+            return None;
+        }
+        Some(PerfectHash {
+            slots,
+            buckets,
+            start: section.start + 8,
+        })
+    }
+
+    /// The file offset of the slot for a key hash.
+    pub(crate) fn slot_offset(&self, map: &[u8], khash: u64) -> u32 {
+        let x = self.start + bucket(khash, self.buckets) as usize * 4;
+        let displacement = uint32::unpack(&map[x..x + 4]);
+        let slots = self.start + self.buckets as usize * 4;
+        (slots + slot(khash, displacement, self.slots) as usize * 4) as u32
+    }
+
+    /// The file offsets of part `i` of the slots, when they are split
+    /// into `parts` roughly equal parts.
+    #[cfg(feature = "rayon")]
+    pub(crate) fn slot_range(&self, i: usize, parts: usize) -> Range<usize> {
+        let slots = self.start + self.buckets as usize * 4;
+        let n = self.slots as usize;
+        slots + i * n / parts * 4..slots + (i + 1) * n / parts * 4
+    }
+}
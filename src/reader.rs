@@ -1,9 +1,25 @@
-use filebuffer::FileBuffer;
+use std::borrow::Cow;
 use std::cmp::min;
+use std::collections::BTreeMap;
+use std::fs;
+use std::hint;
 use std::io;
+use std::mem;
+use std::ops::{Bound, Range, RangeBounds};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path;
+use std::sync::Arc;
 
-use crate::hash::hash;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+use crate::bloom::Bloom;
+use crate::hash::{hash, hash64};
+use crate::mmap::{self, Advice, Mmap};
+use crate::normalize::{self, Normalizer};
+use crate::perfect::PerfectHash;
+use crate::trailer;
 use crate::uint32;
 
 pub use std::io::Result;
@@ -22,12 +38,28 @@ const KEYSIZE: usize = 32;
 /// }
 /// ```
 pub struct CDB {
-    file: FileBuffer,
+    file: fs::File,
+    map: Mmap,
     size: usize,
+    bloom: Option<Bloom>,
+    perfect: Option<PerfectHash>,
+    key_index: Option<Range<usize>>,
+    normalizer: Option<Arc<dyn Normalizer>>,
 }
 
 fn err_badfile<T>() -> Result<T> {
-    Err(io::Error::new(io::ErrorKind::Other, "Invalid file format"))
+    Err(io::Error::other("Invalid file format"))
+}
+
+fn err_normalizer<T>(msg: &str) -> Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+fn err_badcursor<T>() -> Result<T> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Cursor is not at a record boundary",
+    ))
 }
 
 impl CDB {
@@ -39,12 +71,114 @@ impl CDB {
     /// let cdb = cdb::CDB::open("tests/test1.cdb").unwrap();
     /// ```
     pub fn open<P: AsRef<path::Path>>(filename: P) -> Result<CDB> {
-        let file = FileBuffer::open(&filename)?;
-        if file.len() < 2048 + 8 + 8 || file.len() > 0xffffffff {
+        CDB::from_file(fs::File::open(filename)?)
+    }
+
+    /// Creates a CDB reader from an already opened file.
+    ///
+    /// If the file was written with a key normalizer, it must be one
+    /// of the built-in normalizers, or else an error of kind
+    /// `InvalidData` is returned.
+    pub fn from_file(file: fs::File) -> Result<CDB> {
+        CDB::from_file_with(file, None)
+    }
+
+    /// Opens the named file, which was written with `normalizer`, and
+    /// returns the CDB reader.
+    ///
+    /// This is needed to read files written with a custom
+    /// [`Normalizer`](trait.Normalizer.html). If the file was written
+    /// without a normalizer, or with one of a different name, an error
+    /// of kind `InvalidData` is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let cdb = cdb::CDB::open_with_normalizer("hosts.cdb", cdb::AsciiLowercase).unwrap();
+    /// if let Some(record) = cdb.get(b"Example.COM") {
+    ///     println!("{:?}", record.unwrap());
+    /// }
+    /// ```
+    pub fn open_with_normalizer<P, N>(filename: P, normalizer: N) -> Result<CDB>
+    where
+        P: AsRef<path::Path>,
+        N: Normalizer + 'static,
+    {
+        CDB::from_file_with_normalizer(fs::File::open(filename)?, normalizer)
+    }
+
+    /// Creates a CDB reader from an already opened file, which was
+    /// written with `normalizer`.
+    ///
+    /// See [`open_with_normalizer`](#method.open_with_normalizer).
+    pub fn from_file_with_normalizer<N: Normalizer + 'static>(
+        file: fs::File,
+        normalizer: N,
+    ) -> Result<CDB> {
+        CDB::from_file_with(file, Some(Arc::new(normalizer)))
+    }
+
+    pub(crate) fn from_file_with(
+        file: fs::File,
+        expected: Option<Arc<dyn Normalizer>>,
+    ) -> Result<CDB> {
+        let size = file.metadata()?.len();
+        if !(2048 + 8 + 8..=0xffffffff).contains(&size) {
             return err_badfile();
         }
-        let size = file.len();
-        Ok(CDB { file, size })
+        let map = Mmap::map(&file)?;
+        let size = map.len();
+        let mut bloom = None;
+        let mut perfect = None;
+        let mut key_index = None;
+        let mut normalizer = None;
+        for (tag, section) in trailer::parse(&map)? {
+            match tag {
+                trailer::BLOOM => {
+                    bloom = Some(Bloom::parse(&map, section).map_or_else(err_badfile, Ok)?)
+                }
+                trailer::PERFECT_HASH => {
+                    perfect = Some(PerfectHash::parse(&map, section).map_or_else(err_badfile, Ok)?)
+                }
+                trailer::KEY_INDEX if section.len() % 4 == 0 => key_index = Some(section),
+                trailer::KEY_INDEX => return err_badfile(),
+                trailer::NORMALIZER => {
+                    let name = &map[section];
+                    normalizer = match &expected {
+                        Some(expected) if expected.name().as_bytes() == name => {
+                            Some(expected.clone())
+                        }
+                        Some(_) => return err_normalizer("Key normalizer does not match file"),
+                        None => match normalize::builtin(name) {
+                            Some(builtin) => Some(builtin),
+                            None => return err_normalizer("Unknown key normalizer"),
+                        },
+                    };
+                }
+                _ => (),
+            }
+        }
+        if expected.is_some() && normalizer.is_none() {
+            return err_normalizer("File has no key normalizer");
+        }
+        Ok(CDB {
+            file,
+            map,
+            size,
+            bloom,
+            perfect,
+            key_index,
+            normalizer,
+        })
+    }
+
+    /// The underlying file the database was read from.
+    ///
+    /// Together with [`RecordLocation`](struct.RecordLocation.html),
+    /// this allows values to be copied straight out of the file, for
+    /// example with `sendfile` or `copy_file_range`.
+    pub fn file(&self) -> &fs::File {
+        &self.file
     }
 
     fn read(&self, buf: &mut [u8], pos: u32) -> Result<usize> {
@@ -53,13 +187,44 @@ impl CDB {
         if pos + len > self.size {
             return err_badfile();
         }
-        buf.copy_from_slice(&self.file[pos..pos + len]);
+        buf.copy_from_slice(&self.map[pos..pos + len]);
         Ok(len)
     }
 
+    fn slice(&self, pos: u32, len: u32) -> Result<&[u8]> {
+        let pos = pos as usize;
+        let end = pos + len as usize;
+        if end > self.size {
+            return err_badfile();
+        }
+        Ok(&self.map[pos..end])
+    }
+
+    /// Locate the record starting at `pos`, which must end at or
+    /// before `end`.
+    fn record(&self, pos: u32, end: u32) -> Result<RecordLocation> {
+        let mut buf = [0u8; 8];
+        self.read(&mut buf, pos)?;
+        let (klen, dlen) = uint32::unpack2(&buf);
+        if pos as u64 + 8 + klen as u64 + dlen as u64 > end as u64 {
+            return err_badfile();
+        }
+        Ok(RecordLocation {
+            key_offset: pos + 8,
+            key_len: klen,
+            value_offset: pos + 8 + klen,
+            value_len: dlen,
+        })
+    }
+
+    /// The end of the records, where the hash tables start.
+    fn data_end(&self) -> u32 {
+        uint32::unpack(&self.map[0..4]).min(self.size as u32)
+    }
+
     fn hash_table(&self, khash: u32) -> (u32, u32, u32) {
         let x = ((khash as usize) & 0xff) << 3;
-        let (hpos, hslots) = uint32::unpack2(&self.file[x..x + 8]);
+        let (hpos, hslots) = uint32::unpack2(&self.map[x..x + 8]);
         let kpos = if hslots > 0 {
             hpos + (((khash >> 8) % hslots) << 3)
         } else {
@@ -68,8 +233,16 @@ impl CDB {
         (hpos, hslots, kpos)
     }
 
+    /// Returns `false` if the Bloom filter, if any, shows that there is
+    /// no key with this hash.
+    fn may_contain(&self, khash: u32) -> bool {
+        self.bloom
+            .as_ref()
+            .map_or(true, |bloom| bloom.may_contain(&self.map, khash))
+    }
+
     fn match_key(&self, key: &[u8], pos: u32) -> Result<bool> {
-        let mut buf = [0 as u8; KEYSIZE];
+        let mut buf = [0u8; KEYSIZE];
         let mut len = key.len();
         let mut pos = pos;
         let mut keypos = 0;
@@ -101,6 +274,53 @@ impl CDB {
         self.find(key).next()
     }
 
+    /// Find the first record for each of a set of keys.
+    ///
+    /// The results are returned in the same order as the keys. Rather
+    /// than probing each key in turn, the keys are first hashed and
+    /// then probed in the order of their hash table slots in the file,
+    /// which reduces page faults when looking up many keys at once.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let cdb = cdb::CDB::open("tests/test1.cdb").unwrap();
+    /// let keys: [&[u8]; 3] = [b"one", b"two", b"three"];
+    /// let results = cdb.get_many(&keys);
+    /// assert_eq!(results[1].as_ref().unwrap().as_ref().unwrap(), b"Goodbye");
+    /// assert!(results[2].is_none());
+    /// ```
+    pub fn get_many(&self, keys: &[&[u8]]) -> Vec<Option<Result<Vec<u8>>>> {
+        let mut iters: Vec<(usize, CDBValueIter)> = keys
+            .iter()
+            .map(|key| CDBValueIter::find_borrowed(self, key))
+            .enumerate()
+            .collect();
+        // Slot positions increase with the header bucket number, so
+        // this sorts by both bucket and slot.
+        iters.sort_by_key(|(_, iter)| iter.kpos);
+        let mut results: Vec<_> = keys.iter().map(|_| None).collect();
+        for (i, mut iter) in iters {
+            results[i] = iter.next();
+        }
+        results
+    }
+
+    /// Find the first record with a pre-hashed key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let key = cdb::HashedKey::new(b"two");
+    /// let cdb = cdb::CDB::open("tests/test1.cdb").unwrap();
+    /// if let Some(record) = cdb.get_hashed(&key) {
+    ///     println!("{:?}", record.unwrap());
+    /// }
+    /// ```
+    pub fn get_hashed<'a>(&'a self, key: &'a HashedKey) -> Option<Result<Vec<u8>>> {
+        self.find_hashed(key).next()
+    }
+
     /// Find all records with the named key. The returned iterator
     /// produces each value associated with the key.
     ///
@@ -113,10 +333,77 @@ impl CDB {
     ///     println!("{:?}", result.unwrap());
     /// }
     /// ```
-    pub fn find(&self, key: &[u8]) -> CDBValueIter {
+    pub fn find(&self, key: &[u8]) -> CDBValueIter<'_> {
         CDBValueIter::find(self, key)
     }
 
+    /// Find the first record with the named key, returning a reader
+    /// over its value instead of a copy of it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let cdb = cdb::CDB::open("tests/test1.cdb").unwrap();
+    /// if let Some(reader) = cdb.get_reader(b"two") {
+    ///     let mut reader = reader.unwrap();
+    ///     std::io::copy(&mut reader, &mut std::io::stdout()).unwrap();
+    /// }
+    /// ```
+    pub fn get_reader(&self, key: &[u8]) -> Option<Result<CDBValueReader<'_>>> {
+        self.find_readers(key).next()
+    }
+
+    /// Find all records with the named key. The returned iterator
+    /// produces a reader over each value associated with the key.
+    pub fn find_readers(&self, key: &[u8]) -> CDBValueReaderIter<'_> {
+        CDBValueReaderIter {
+            iter: CDBValueIter::find(self, key),
+        }
+    }
+
+    /// Find all records with the named key. The returned iterator
+    /// produces the location of each record in the file instead of
+    /// its value.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let cdb = cdb::CDB::open("tests/test1.cdb").unwrap();
+    ///
+    /// for result in cdb.find_locations(b"one") {
+    ///     let location = result.unwrap();
+    ///     println!("{} bytes at {}", location.value_len, location.value_offset);
+    /// }
+    /// ```
+    pub fn find_locations(&self, key: &[u8]) -> CDBLocationIter<'_> {
+        CDBLocationIter {
+            iter: CDBValueIter::find(self, key),
+        }
+    }
+
+    /// Find all records with a pre-hashed key.
+    ///
+    /// This behaves exactly like [`find`](#method.find), but neither
+    /// rehashes nor copies the key, unless the file has a key
+    /// normalizer, which must be applied to the key first.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let key = cdb::HashedKey::new(b"one");
+    /// let cdb = cdb::CDB::open("tests/test1.cdb").unwrap();
+    ///
+    /// for result in cdb.find_hashed(&key) {
+    ///     println!("{:?}", result.unwrap());
+    /// }
+    /// ```
+    pub fn find_hashed<'a>(&'a self, key: &'a HashedKey) -> CDBValueIter<'a> {
+        if self.normalizer.is_some() {
+            return CDBValueIter::find_borrowed(self, &key.key);
+        }
+        CDBValueIter::start(self, Cow::Borrowed(&key.key), key.hash, Some(key.hash64))
+    }
+
     /// Iterate over all the `(key, value)` pairs in the database.
     ///
     /// # Examples
@@ -128,8 +415,487 @@ impl CDB {
     ///     println!("{:?} => {:?}", key, value);
     /// }
     /// ````
-    pub fn iter(&self) -> CDBKeyValueIter {
-        CDBKeyValueIter::start(&self)
+    pub fn iter(&self) -> CDBKeyValueIter<'_> {
+        CDBKeyValueIter::start(self)
+    }
+
+    /// Iterate over the keys of all the records in the database,
+    /// without reading their values. Keys with multiple records are
+    /// produced once for each record.
+    pub fn keys(&self) -> CDBKeyIter<'_> {
+        CDBKeyIter {
+            iter: CDBKeyValueIter::start(self),
+        }
+    }
+
+    /// Iterate over the distinct keys in the database.
+    ///
+    /// Each key is produced only once, at the position of its first
+    /// record. Instead of remembering every key seen so far, each key
+    /// is looked up in the hash tables to determine if the current
+    /// record is the first one with that key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let cdb = cdb::CDB::open("tests/test1.cdb").unwrap();
+    /// for result in cdb.distinct_keys() {
+    ///     println!("{:?}", result.unwrap());
+    /// }
+    /// ```
+    pub fn distinct_keys(&self) -> CDBDistinctKeyIter<'_> {
+        CDBDistinctKeyIter {
+            iter: CDBKeyValueIter::start(self),
+        }
+    }
+
+    /// Iterate over the records in the database grouped by key.
+    ///
+    /// Each distinct key is produced once, in the order of its first
+    /// record, together with an iterator over all of its values.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let cdb = cdb::CDB::open("tests/test1.cdb").unwrap();
+    /// for result in cdb.groups() {
+    ///     let (key, values) = result.unwrap();
+    ///     for value in values {
+    ///         println!("{:?} => {:?}", key, value.unwrap());
+    ///     }
+    /// }
+    /// ```
+    pub fn groups(&self) -> CDBGroupIter<'_> {
+        CDBGroupIter {
+            keys: self.distinct_keys(),
+        }
+    }
+
+    /// Read the entire database into a map from each key to all of its
+    /// values.
+    pub fn to_btree_map(&self) -> Result<BTreeMap<Vec<u8>, Vec<Vec<u8>>>> {
+        let mut map = BTreeMap::new();
+        for group in self.groups() {
+            let (key, values) = group?;
+            map.insert(key, values.collect::<Result<Vec<_>>>()?);
+        }
+        Ok(map)
+    }
+
+    /// Iterate over the `(key, value)` pairs in the database, resuming
+    /// at a cursor obtained from an earlier iterator.
+    ///
+    /// The cursor is validated against the file, and an error is
+    /// returned if it does not point at a record boundary.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let cdb = cdb::CDB::open("tests/test1.cdb").unwrap();
+    /// let mut iter = cdb.iter();
+    /// iter.next();
+    /// let offset = iter.cursor().offset();
+    ///
+    /// // ...later, possibly in another process
+    /// let cursor = cdb::Cursor::from_offset(offset);
+    /// for result in cdb.iter_from(cursor).unwrap() {
+    ///     let (key, value) = result.unwrap();
+    ///     println!("{:?} => {:?}", key, value);
+    /// }
+    /// ```
+    pub fn iter_from(&self, cursor: Cursor) -> Result<CDBKeyValueIter<'_>> {
+        CDBKeyValueIter::resume(self, cursor)
+    }
+
+    /// Iterate over all the `(key, value)` pairs in the database in
+    /// parallel.
+    ///
+    /// The work is split across the 256 hash tables, or the slots of
+    /// the perfect hash index, which list the positions of the records,
+    /// so the pairs are produced in no particular order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rayon::prelude::*;
+    ///
+    /// let cdb = cdb::CDB::open("tests/test1.cdb").unwrap();
+    /// let total: usize = cdb
+    ///     .par_iter()
+    ///     .map(|result| result.unwrap().1.len())
+    ///     .sum();
+    /// ```
+    #[cfg(feature = "rayon")]
+    pub fn par_iter(&self) -> impl ParallelIterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_ {
+        (0..256usize).into_par_iter().flat_map_iter(move |i| {
+            // Each slot ends with the position of a record.
+            let (table, width) = match &self.perfect {
+                Some(perfect) => (Ok(&self.map[perfect.slot_range(i, 256)]), 4),
+                None => {
+                    let x = i << 3;
+                    let (hpos, hslots) = uint32::unpack2(&self.map[x..x + 8]);
+                    let table = hslots
+                        .checked_mul(8)
+                        .map_or_else(err_badfile, |len| self.slice(hpos, len));
+                    (table, 8)
+                }
+            };
+            let (table, error) = match table {
+                Ok(table) => (table, None),
+                Err(err) => (&[][..], Some(err)),
+            };
+            let records = table.chunks(width).filter_map(move |slot| {
+                let pos = uint32::unpack(&slot[width - 4..]);
+                if pos == 0 {
+                    return None;
+                }
+                if pos < 2048 {
+                    return Some(err_badfile());
+                }
+                Some(self.record(pos, self.data_end()).map(|location| {
+                    let key = self.map[location.key_range()].to_vec();
+                    let value = self.map[location.value_range()].to_vec();
+                    (key, value)
+                }))
+            });
+            error.map(Err).into_iter().chain(records)
+        })
+    }
+
+    /// Iterate over the records whose keys start with `prefix`, in key
+    /// order.
+    ///
+    /// This requires the sorted key index written with
+    /// [`CDBMake::set_key_index`](struct.CDBMake.html#method.set_key_index),
+    /// which is binary searched for the first matching key. Without
+    /// it, the iterator produces a single error of kind `Unsupported`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let cdb = cdb::CDB::open("users.cdb").unwrap();
+    /// for result in cdb.prefix(b"user:123:") {
+    ///     let (key, value) = result.unwrap();
+    ///     println!("{:?} => {:?}", key, value);
+    /// }
+    /// ```
+    pub fn prefix(&self, prefix: &[u8]) -> CDBRangeIter<'_> {
+        let prefix = &normalize::apply(&self.normalizer, prefix)[..];
+        CDBRangeIter::search(self, |key| key < prefix, |key| key.starts_with(prefix))
+    }
+
+    /// Iterate over the records whose keys lie within `range`, in key
+    /// order.
+    ///
+    /// Like [`prefix`](#method.prefix), this requires the sorted key
+    /// index.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let cdb = cdb::CDB::open("users.cdb").unwrap();
+    /// for result in cdb.range(&b"user:100"[..]..&b"user:200"[..]) {
+    ///     let (key, value) = result.unwrap();
+    ///     println!("{:?} => {:?}", key, value);
+    /// }
+    /// ```
+    pub fn range<'k, R: RangeBounds<&'k [u8]>>(&self, range: R) -> CDBRangeIter<'_> {
+        let normalize = |bound| match bound {
+            Bound::Included(key) => Bound::Included(normalize::apply(&self.normalizer, key)),
+            Bound::Excluded(key) => Bound::Excluded(normalize::apply(&self.normalizer, key)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let start = normalize(range.start_bound().cloned());
+        let end = normalize(range.end_bound().cloned());
+        CDBRangeIter::search(
+            self,
+            |key| match &start {
+                Bound::Included(start) => key < start,
+                Bound::Excluded(start) => key <= start,
+                Bound::Unbounded => false,
+            },
+            |key| match &end {
+                Bound::Included(end) => key <= end,
+                Bound::Excluded(end) => key < end,
+                Bound::Unbounded => true,
+            },
+        )
+    }
+
+    /// The key of the record at position `i` in the sorted key index.
+    fn index_key(&self, index: &Range<usize>, i: usize) -> Result<&[u8]> {
+        let x = index.start + i * 4;
+        let pos = uint32::unpack(&self.map[x..x + 4]);
+        let location = self.record(pos, self.data_end())?;
+        Ok(&self.map[location.key_range()])
+    }
+
+    /// Find the first position in the sorted key index at which `pred`
+    /// is false for the normalized key, given that it is true for all
+    /// the earlier positions.
+    fn index_partition<F>(&self, index: &Range<usize>, mut lo: usize, pred: F) -> Result<usize>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let mut hi = index.len() / 4;
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let key = self.index_key(index, mid)?;
+            if pred(&normalize::apply(&self.normalizer, key)) {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        Ok(lo)
+    }
+
+    /// Iterate over the locations of all the records in the database.
+    pub fn iter_locations(&self) -> CDBKeyValueLocationIter<'_> {
+        CDBKeyValueLocationIter {
+            iter: CDBKeyValueIter::start(self),
+        }
+    }
+
+    /// Advise the kernel how the file will be accessed from now on.
+    ///
+    /// For example, a database which was opened with
+    /// [`Advice::Random`](enum.Advice.html#variant.Random) for lookups
+    /// can be switched to
+    /// [`Advice::Sequential`](enum.Advice.html#variant.Sequential)
+    /// before iterating over all of it.
+    pub fn advise(&self, advice: Advice) -> Result<()> {
+        self.map.advise(advice)
+    }
+
+    /// Lock part or all of the file into memory, so it is never paged
+    /// out. The pages stay locked until the database is dropped.
+    ///
+    /// Locking memory usually requires privileges or a sufficient
+    /// `RLIMIT_MEMLOCK` resource limit, and fails otherwise. On
+    /// platforms other than Unix it always fails with an error of kind
+    /// `Unsupported`.
+    pub fn lock(&self, lock: MemoryLock) -> Result<()> {
+        match lock {
+            MemoryLock::Tables => self
+                .table_ranges()
+                .iter()
+                .try_for_each(|range| self.map.lock(range.clone())),
+            MemoryLock::File => self.map.lock(0..self.size),
+        }
+    }
+
+    /// Read every page of the header and hash tables, so the first
+    /// lookups do not have to wait for them to be paged in.
+    ///
+    /// Any indexes after the hash tables are read as well.
+    pub fn prefetch(&self) {
+        let page = mmap::page_size();
+        for range in self.table_ranges() {
+            // Start from the beginning of the first page, so that
+            // stepping a page at a time touches every page in the range.
+            let start = range.start / page * page;
+            for pos in (start..range.end).step_by(page) {
+                hint::black_box(self.map[pos]);
+            }
+        }
+    }
+
+    /// The parts of the file read by lookups, which are the header and
+    /// everything after the records.
+    fn table_ranges(&self) -> [Range<usize>; 2] {
+        let tables = (uint32::unpack(&self.map[0..4]) as usize).clamp(2048, self.size);
+        [0..2048, tables..self.size]
+    }
+}
+
+/// Which parts of a [`CDB`](struct.CDB.html) file to lock into memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryLock {
+    /// The header and hash tables, along with any indexes after them,
+    /// which are all that lookups need besides the matching records.
+    Tables,
+    /// The whole file.
+    File,
+}
+
+/// Options for opening a [`CDB`](struct.CDB.html) file, controlling
+/// how it is kept in memory.
+///
+/// # Examples
+///
+/// ```
+/// let cdb = cdb::OpenOptions::new()
+///     .advice(cdb::Advice::Random)
+///     .prefetch(true)
+///     .open("tests/test1.cdb")
+///     .unwrap();
+/// assert_eq!(cdb.get(b"two").unwrap().unwrap(), b"Goodbye");
+/// ```
+#[derive(Clone, Default)]
+pub struct OpenOptions {
+    advice: Option<Advice>,
+    lock: Option<MemoryLock>,
+    prefetch: bool,
+    normalizer: Option<Arc<dyn Normalizer>>,
+}
+
+impl OpenOptions {
+    /// Create a new set of options, which open files just like
+    /// [`CDB::open`](struct.CDB.html#method.open).
+    pub fn new() -> OpenOptions {
+        OpenOptions::default()
+    }
+
+    /// Advise the kernel how the file will be accessed.
+    ///
+    /// See [`CDB::advise`](struct.CDB.html#method.advise).
+    pub fn advice(&mut self, advice: Advice) -> &mut OpenOptions {
+        self.advice = Some(advice);
+        self
+    }
+
+    /// Lock part or all of the file into memory.
+    ///
+    /// See [`CDB::lock`](struct.CDB.html#method.lock).
+    pub fn lock(&mut self, lock: MemoryLock) -> &mut OpenOptions {
+        self.lock = Some(lock);
+        self
+    }
+
+    /// Read the header and hash tables into memory when the file is
+    /// opened.
+    ///
+    /// See [`CDB::prefetch`](struct.CDB.html#method.prefetch).
+    pub fn prefetch(&mut self, prefetch: bool) -> &mut OpenOptions {
+        self.prefetch = prefetch;
+        self
+    }
+
+    /// Require the file to have been written with `normalizer`.
+    ///
+    /// See [`CDB::open_with_normalizer`](struct.CDB.html#method.open_with_normalizer).
+    pub fn normalizer<N: Normalizer + 'static>(&mut self, normalizer: N) -> &mut OpenOptions {
+        self.normalizer = Some(Arc::new(normalizer));
+        self
+    }
+
+    /// Open the named file with these options.
+    pub fn open<P: AsRef<path::Path>>(&self, filename: P) -> Result<CDB> {
+        self.open_file(fs::File::open(filename)?)
+    }
+
+    /// Create a CDB reader from an already opened file with these
+    /// options.
+    pub fn open_file(&self, file: fs::File) -> Result<CDB> {
+        let cdb = CDB::from_file_with(file, self.normalizer.clone())?;
+        if let Some(advice) = self.advice {
+            cdb.advise(advice)?;
+        }
+        if let Some(lock) = self.lock {
+            cdb.lock(lock)?;
+        }
+        if self.prefetch {
+            cdb.prefetch();
+        }
+        Ok(cdb)
+    }
+}
+
+macro_rules! iter_try {
+    ( $e:expr ) => {
+        match $e {
+            Err(x) => {
+                return Some(Err(x));
+            }
+            Ok(y) => y,
+        }
+    };
+}
+
+#[cfg(unix)]
+impl AsRawFd for CDB {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// The location of a record's key and value within the CDB file.
+///
+/// All offsets are in bytes from the start of the file, suitable for
+/// use with the file returned by [`CDB::file`](struct.CDB.html#method.file).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecordLocation {
+    pub key_offset: u32,
+    pub key_len: u32,
+    pub value_offset: u32,
+    pub value_len: u32,
+}
+
+impl RecordLocation {
+    fn key_range(&self) -> Range<usize> {
+        self.key_offset as usize..(self.key_offset + self.key_len) as usize
+    }
+
+    fn value_range(&self) -> Range<usize> {
+        self.value_offset as usize..(self.value_offset + self.value_len) as usize
+    }
+}
+
+/// A saved position within an iteration over all the records in a CDB.
+///
+/// A cursor is just the file offset of a record, so it may be persisted
+/// using [`offset`](#method.offset) and restored using
+/// [`from_offset`](#method.from_offset). It is validated when it is
+/// passed to [`CDB::iter_from`](struct.CDB.html#method.iter_from).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Cursor {
+    pos: u32,
+}
+
+impl Cursor {
+    /// Restore a cursor from a file offset.
+    pub fn from_offset(offset: u32) -> Cursor {
+        Cursor { pos: offset }
+    }
+
+    /// The file offset of the record the cursor points at.
+    pub fn offset(&self) -> u32 {
+        self.pos
+    }
+}
+
+/// A key with its hash precomputed.
+///
+/// Looking up the same key repeatedly, or in many different files,
+/// with [`CDB::find_hashed`](struct.CDB.html#method.find_hashed) or
+/// [`CDB::get_hashed`](struct.CDB.html#method.get_hashed) avoids
+/// rehashing and copying the key for every lookup.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HashedKey {
+    key: Vec<u8>,
+    hash: u32,
+    /// The hash used with a perfect hash index.
+    hash64: u64,
+}
+
+impl HashedKey {
+    /// Create a new pre-hashed key.
+    pub fn new(key: &[u8]) -> HashedKey {
+        HashedKey::from(key.to_vec())
+    }
+
+    /// The bytes of the key.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+impl From<Vec<u8>> for HashedKey {
+    fn from(key: Vec<u8>) -> HashedKey {
+        let hash = hash(&key);
+        let hash64 = hash64(&key);
+        HashedKey { key, hash, hash64 }
     }
 }
 
@@ -141,8 +907,10 @@ pub type CDBIter<'a> = CDBValueIter<'a>;
 /// See [`CDB::find`](struct.CDB.html#method.find)
 pub struct CDBValueIter<'a> {
     cdb: &'a CDB,
-    key: Vec<u8>,
+    key: Cow<'a, [u8]>,
     khash: u32,
+    /// The one record position found through the perfect hash index.
+    perfect_pos: u32,
     kloop: u32,
     kpos: u32,
     hpos: u32,
@@ -153,17 +921,43 @@ pub struct CDBValueIter<'a> {
 
 impl<'a> CDBValueIter<'a> {
     fn find(cdb: &'a CDB, key: &[u8]) -> Self {
-        let khash = hash(key);
-        let (hpos, hslots, kpos) = cdb.hash_table(khash);
+        let key = normalize::apply(&cdb.normalizer, key).into_owned();
+        let khash = hash(&key);
+        CDBValueIter::start(cdb, Cow::Owned(key), khash, None)
+    }
+
+    /// Like `find`, but borrows the key where possible.
+    fn find_borrowed(cdb: &'a CDB, key: &'a [u8]) -> Self {
+        let key = normalize::apply(&cdb.normalizer, key);
+        let khash = hash(&key);
+        CDBValueIter::start(cdb, key, khash, None)
+    }
+
+    /// Start searching for a key with its hash, and its 64 bit hash if
+    /// already known.
+    fn start(cdb: &'a CDB, key: Cow<'a, [u8]>, khash: u32, khash64: Option<u64>) -> Self {
+        let (hpos, mut hslots, mut kpos) = cdb.hash_table(khash);
+        let mut perfect_pos = 0;
+        if !cdb.may_contain(khash) {
+            // Skip probing the table entirely.
+            hslots = 0;
+        } else if let Some(perfect) = &cdb.perfect {
+            // The slot always lies within the index, which was checked
+            // when the file was opened.
+            let khash64 = khash64.unwrap_or_else(|| hash64(&key));
+            kpos = perfect.slot_offset(&cdb.map, khash64);
+            perfect_pos = uint32::unpack(&cdb.map[kpos as usize..]);
+        }
 
         CDBValueIter {
-            cdb: cdb,
-            key: key.into_iter().map(|x| *x).collect(),
-            khash: khash,
+            cdb,
+            key,
+            khash,
+            perfect_pos,
             kloop: 0,
-            kpos: kpos,
-            hpos: hpos,
-            hslots: hslots,
+            kpos,
+            hpos,
+            hslots,
             dpos: 0,
             dlen: 0,
         }
@@ -174,24 +968,19 @@ impl<'a> CDBValueIter<'a> {
         self.cdb.read(&mut result[..], self.dpos)?;
         Ok(result)
     }
-}
 
-macro_rules! iter_try {
-    ( $e:expr ) => {
-        match $e {
-            Err(x) => {
-                return Some(Err(x));
+    /// Advance to the next record matching the key, returning its
+    /// position and leaving the location of its value in `dpos` and
+    /// `dlen`.
+    fn next_pos(&mut self) -> Option<Result<u32>> {
+        if self.perfect_pos != 0 {
+            let pos = mem::replace(&mut self.perfect_pos, 0);
+            if iter_try!(self.match_record(pos)) {
+                return Some(Ok(pos));
             }
-            Ok(y) => y,
         }
-    };
-}
-
-impl<'a> Iterator for CDBValueIter<'a> {
-    type Item = Result<Vec<u8>>;
-    fn next(&mut self) -> Option<Self::Item> {
         while self.kloop < self.hslots {
-            let mut buf = [0 as u8; 8];
+            let mut buf = [0u8; 8];
             let kpos = self.kpos;
             iter_try!(self.cdb.read(&mut buf, kpos));
             let (khash, pos) = uint32::unpack2(&buf);
@@ -203,25 +992,229 @@ impl<'a> Iterator for CDBValueIter<'a> {
             if self.kpos == self.hpos + (self.hslots << 3) {
                 self.kpos = self.hpos;
             }
-            if khash == self.khash {
-                iter_try!(self.cdb.read(&mut buf, pos));
-                let (klen, dlen) = uint32::unpack2(&buf);
-                if klen as usize == self.key.len() {
-                    if iter_try!(self.cdb.match_key(&self.key[..], pos + 8)) {
-                        self.dlen = dlen;
-                        self.dpos = pos + 8 + self.key.len() as u32;
-                        return Some(self.read_vec());
-                    }
-                }
+            if khash == self.khash && iter_try!(self.match_record(pos)) {
+                return Some(Ok(pos));
             }
         }
         None
     }
+
+    /// Check if the record at `pos` has the key, and if so leave the
+    /// location of its value in `dpos` and `dlen`.
+    fn match_record(&mut self, pos: u32) -> Result<bool> {
+        let mut buf = [0u8; 8];
+        self.cdb.read(&mut buf, pos)?;
+        let (klen, dlen) = uint32::unpack2(&buf);
+        let matched = match &self.cdb.normalizer {
+            None => {
+                klen as usize == self.key.len() && self.cdb.match_key(&self.key[..], pos + 8)?
+            }
+            Some(normalizer) => normalizer.normalize(self.cdb.slice(pos + 8, klen)?) == self.key,
+        };
+        if matched {
+            self.dlen = dlen;
+            self.dpos = pos + 8 + klen;
+        }
+        Ok(matched)
+    }
+}
+
+impl<'a> Iterator for CDBValueIter<'a> {
+    type Item = Result<Vec<u8>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_pos().map(|pos| pos.and_then(|_| self.read_vec()))
+    }
+}
+
+/// Iterator over the locations of a set of records in the CDB with
+/// the same key.
+///
+/// See [`CDB::find_locations`](struct.CDB.html#method.find_locations)
+pub struct CDBLocationIter<'a> {
+    iter: CDBValueIter<'a>,
+}
+
+impl<'a> Iterator for CDBLocationIter<'a> {
+    type Item = Result<RecordLocation>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let iter = &mut self.iter;
+        iter.next_pos().map(|pos| {
+            pos.map(|pos| RecordLocation {
+                key_offset: pos + 8,
+                key_len: iter.dpos - pos - 8,
+                value_offset: iter.dpos,
+                value_len: iter.dlen,
+            })
+        })
+    }
+}
+
+/// Iterator over readers for a set of records in the CDB with the same
+/// key.
+///
+/// See [`CDB::find_readers`](struct.CDB.html#method.find_readers)
+pub struct CDBValueReaderIter<'a> {
+    iter: CDBValueIter<'a>,
+}
+
+impl<'a> Iterator for CDBValueReaderIter<'a> {
+    type Item = Result<CDBValueReader<'a>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let iter = &mut self.iter;
+        iter.next_pos().map(|pos| {
+            pos.and_then(|_| iter.cdb.slice(iter.dpos, iter.dlen))
+                .map(|data| CDBValueReader {
+                    data: io::Cursor::new(data),
+                })
+        })
+    }
+}
+
+/// A reader over a single value in the CDB.
+///
+/// The value is read directly from the mapped file, so arbitrarily
+/// large values can be copied out with bounded memory.
+///
+/// See [`CDB::get_reader`](struct.CDB.html#method.get_reader)
+pub struct CDBValueReader<'a> {
+    data: io::Cursor<&'a [u8]>,
+}
+
+impl<'a> CDBValueReader<'a> {
+    /// The total length of the value.
+    pub fn len(&self) -> u64 {
+        self.data.get_ref().len() as u64
+    }
+
+    /// Returns `true` if the value is empty.
+    pub fn is_empty(&self) -> bool {
+        self.data.get_ref().is_empty()
+    }
+}
+
+impl<'a> io::Read for CDBValueReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.data.read(buf)
+    }
+}
+
+impl<'a> io::BufRead for CDBValueReader<'a> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        self.data.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.data.consume(amt)
+    }
+}
+
+impl<'a> io::Seek for CDBValueReader<'a> {
+    fn seek(&mut self, pos: io::SeekFrom) -> Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+/// Iterator over the records in the CDB within a range of keys, in key
+/// order.
+///
+/// See [`CDB::prefix`](struct.CDB.html#method.prefix) and
+/// [`CDB::range`](struct.CDB.html#method.range)
+pub struct CDBRangeIter<'a> {
+    cdb: &'a CDB,
+    index: Range<usize>,
+    next: usize,
+    end: usize,
+    error: Option<io::Error>,
+}
+
+impl<'a> CDBRangeIter<'a> {
+    /// Binary search the sorted key index for the keys after those
+    /// which are `before` the range, up to the first which is not
+    /// `within` it.
+    fn search<B, W>(cdb: &'a CDB, before: B, within: W) -> Self
+    where
+        B: Fn(&[u8]) -> bool,
+        W: Fn(&[u8]) -> bool,
+    {
+        let mut iter = CDBRangeIter {
+            cdb,
+            index: 0..0,
+            next: 0,
+            end: 0,
+            error: None,
+        };
+        let index = match &cdb.key_index {
+            Some(index) => index.clone(),
+            None => {
+                iter.error = Some(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "File has no key index",
+                ));
+                return iter;
+            }
+        };
+        let bounds = cdb
+            .index_partition(&index, 0, before)
+            .and_then(|start| Ok((start, cdb.index_partition(&index, start, within)?)));
+        match bounds {
+            Ok((start, end)) => {
+                iter.index = index;
+                iter.next = start;
+                iter.end = end;
+            }
+            Err(err) => iter.error = Some(err),
+        }
+        iter
+    }
+
+    fn read(&self, i: usize) -> Result<(Vec<u8>, Vec<u8>)> {
+        let x = self.index.start + i * 4;
+        let pos = uint32::unpack(&self.cdb.map[x..x + 4]);
+        let location = self.cdb.record(pos, self.cdb.data_end())?;
+        let key = self.cdb.map[location.key_range()].to_vec();
+        let value = self.cdb.map[location.value_range()].to_vec();
+        Ok((key, value))
+    }
+}
+
+impl<'a> Iterator for CDBRangeIter<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.error.take() {
+            return Some(Err(err));
+        }
+        if self.next == self.end {
+            return None;
+        }
+        self.next += 1;
+        Some(self.read(self.next - 1))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.end - self.next + self.error.is_some() as usize;
+        (len, Some(len))
+    }
+}
+
+impl<'a> ExactSizeIterator for CDBRangeIter<'a> {}
+
+impl<'a> DoubleEndedIterator for CDBRangeIter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.error.take() {
+            return Some(Err(err));
+        }
+        if self.next == self.end {
+            return None;
+        }
+        self.end -= 1;
+        Some(self.read(self.end))
+    }
 }
 
 /// Iterator over all the records in the CDB.
 ///
 /// See [`CDB::iter`](struct.CDB.html#method.iter)
+#[derive(Clone)]
 pub struct CDBKeyValueIter<'a> {
     cdb: &'a CDB,
     pos: u32,
@@ -230,36 +1223,177 @@ pub struct CDBKeyValueIter<'a> {
 
 impl<'a> CDBKeyValueIter<'a> {
     fn start(cdb: &'a CDB) -> Self {
-        let data_end = uint32::unpack(&cdb.file[0..4]).min(cdb.size as u32);
+        let data_end = cdb.data_end();
         Self {
             cdb,
             pos: 2048,
             data_end,
         }
     }
+
+    fn resume(cdb: &'a CDB, cursor: Cursor) -> Result<Self> {
+        let mut iter = CDBKeyValueIter::start(cdb);
+        if cursor.pos < iter.pos || cursor.pos > iter.data_end {
+            return err_badcursor();
+        }
+        iter.pos = cursor.pos;
+        if cursor.pos < iter.data_end {
+            // A record boundary must hold a record that can be found
+            // through the hash tables at exactly this position.
+            let location = match iter.clone().next_location() {
+                Some(Ok(location)) => location,
+                _ => return err_badcursor(),
+            };
+            let key = &cdb.map[location.key_range()];
+            let mut records = CDBValueIter::find_borrowed(cdb, key);
+            loop {
+                match records.next_pos() {
+                    None => return err_badcursor(),
+                    Some(pos) => {
+                        if pos? == cursor.pos {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(iter)
+    }
+
+    /// A cursor for the position of the next record this iterator will
+    /// produce.
+    pub fn cursor(&self) -> Cursor {
+        Cursor { pos: self.pos }
+    }
+
+    fn next_location(&mut self) -> Option<Result<RecordLocation>> {
+        if self.pos + 8 >= self.data_end {
+            None
+        } else {
+            let location = iter_try!(self.cdb.record(self.pos, self.data_end));
+            self.pos = location.value_offset + location.value_len;
+            Some(Ok(location))
+        }
+    }
 }
 
 impl<'a> Iterator for CDBKeyValueIter<'a> {
     type Item = Result<(Vec<u8>, Vec<u8>)>;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.pos + 8 >= self.data_end {
-            None
-        } else {
-            let (klen, dlen) =
-                uint32::unpack2(&self.cdb.file[self.pos as usize..self.pos as usize + 8]);
-            if self.pos + klen + dlen >= self.data_end {
-                Some(err_badfile())
-            } else {
-                let kpos = (self.pos + 8) as usize;
-                let dpos = kpos + klen as usize;
-                let mut key = vec![0; klen as usize];
-                let mut value = vec![0; dlen as usize];
-                // Copied from CDB::read
-                key.copy_from_slice(&self.cdb.file[kpos..kpos + klen as usize]);
-                value.copy_from_slice(&self.cdb.file[dpos..dpos + dlen as usize]);
-                self.pos += 8 + klen + dlen;
-                Some(Ok((key, value)))
+        let cdb = self.cdb;
+        self.next_location().map(|location| {
+            location.map(|location| {
+                let key = cdb.map[location.key_range()].to_vec();
+                let value = cdb.map[location.value_range()].to_vec();
+                (key, value)
+            })
+        })
+    }
+}
+
+/// Iterator over the locations of all the records in the CDB.
+///
+/// See [`CDB::iter_locations`](struct.CDB.html#method.iter_locations)
+pub struct CDBKeyValueLocationIter<'a> {
+    iter: CDBKeyValueIter<'a>,
+}
+
+impl<'a> CDBKeyValueLocationIter<'a> {
+    /// A cursor for the position of the next record this iterator will
+    /// examine.
+    pub fn cursor(&self) -> Cursor {
+        self.iter.cursor()
+    }
+}
+
+impl<'a> Iterator for CDBKeyValueLocationIter<'a> {
+    type Item = Result<RecordLocation>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next_location()
+    }
+}
+
+/// Iterator over the keys of all the records in the CDB.
+///
+/// See [`CDB::keys`](struct.CDB.html#method.keys)
+pub struct CDBKeyIter<'a> {
+    iter: CDBKeyValueIter<'a>,
+}
+
+impl<'a> CDBKeyIter<'a> {
+    /// A cursor for the position of the next record this iterator will
+    /// examine.
+    pub fn cursor(&self) -> Cursor {
+        self.iter.cursor()
+    }
+}
+
+impl<'a> Iterator for CDBKeyIter<'a> {
+    type Item = Result<Vec<u8>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let cdb = self.iter.cdb;
+        self.iter
+            .next_location()
+            .map(|location| location.map(|location| cdb.map[location.key_range()].to_vec()))
+    }
+}
+
+/// Iterator over the distinct keys in the CDB.
+///
+/// See [`CDB::distinct_keys`](struct.CDB.html#method.distinct_keys)
+pub struct CDBDistinctKeyIter<'a> {
+    iter: CDBKeyValueIter<'a>,
+}
+
+impl<'a> CDBDistinctKeyIter<'a> {
+    /// A cursor for the position of the next record this iterator will
+    /// examine.
+    pub fn cursor(&self) -> Cursor {
+        self.iter.cursor()
+    }
+}
+
+impl<'a> Iterator for CDBDistinctKeyIter<'a> {
+    type Item = Result<Vec<u8>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let cdb = self.iter.cdb;
+        loop {
+            let location = iter_try!(self.iter.next_location()?);
+            let key = &cdb.map[location.key_range()];
+            // Records with the same key are probed in the order they
+            // were written, so the first match is the first record.
+            let mut first = CDBValueIter::find_borrowed(cdb, key);
+            match first.next_pos() {
+                None => return Some(err_badfile()),
+                Some(Err(err)) => return Some(Err(err)),
+                Some(Ok(pos)) if pos + 8 == location.key_offset => return Some(Ok(key.to_vec())),
+                Some(Ok(_)) => (),
             }
         }
     }
 }
+
+/// Iterator over the records in the CDB grouped by key.
+///
+/// See [`CDB::groups`](struct.CDB.html#method.groups)
+pub struct CDBGroupIter<'a> {
+    keys: CDBDistinctKeyIter<'a>,
+}
+
+impl<'a> CDBGroupIter<'a> {
+    /// A cursor for the position of the next record this iterator will
+    /// examine.
+    pub fn cursor(&self) -> Cursor {
+        self.keys.iter.cursor()
+    }
+}
+
+impl<'a> Iterator for CDBGroupIter<'a> {
+    type Item = Result<(Vec<u8>, CDBValueIter<'a>)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let cdb = self.keys.iter.cdb;
+        self.keys
+            .next()
+            .map(|key| key.map(|key| (key.clone(), CDBValueIter::find(cdb, &key))))
+    }
+}
@@ -0,0 +1,196 @@
+//! Record sorting for deterministic CDB output, spilling sorted runs
+//! to temporary files when the records do not fit in memory.
+
+use std::cmp::Ordering;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::vec;
+
+use crate::uint32;
+
+pub use std::io::Result;
+
+/// The default amount of record data buffered before spilling a run.
+const DEFAULT_MEMORY: usize = 64 << 20;
+
+/// Approximate per-record overhead of the in-memory buffer.
+const RECORD_OVERHEAD: usize = 48;
+
+type Record = (Vec<u8>, Vec<u8>);
+
+pub(crate) type Compare = dyn Fn(&[u8], &[u8], &[u8], &[u8]) -> Ordering + Send;
+
+pub(crate) struct Sorter {
+    compare: Box<Compare>,
+    records: Vec<Record>,
+    size: usize,
+    memory: usize,
+    dir: PathBuf,
+    runs: Vec<Run>,
+}
+
+impl Sorter {
+    pub(crate) fn new(compare: Box<Compare>) -> Sorter {
+        Sorter {
+            compare,
+            records: Vec::new(),
+            size: 0,
+            memory: DEFAULT_MEMORY,
+            dir: env::temp_dir(),
+            runs: Vec::new(),
+        }
+    }
+
+    pub(crate) fn set_memory(&mut self, memory: usize) {
+        self.memory = memory;
+    }
+
+    pub(crate) fn set_dir(&mut self, dir: PathBuf) {
+        self.dir = dir;
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.records.is_empty() && self.runs.is_empty()
+    }
+
+    pub(crate) fn add(&mut self, key: Vec<u8>, data: Vec<u8>) -> Result<()> {
+        self.size += key.len() + data.len() + RECORD_OVERHEAD;
+        self.records.push((key, data));
+        if self.size >= self.memory {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn sort(&mut self) {
+        let compare = &self.compare;
+        // A stable sort keeps records the comparator considers equal
+        // in the order they were added.
+        self.records.sort_by(|a, b| compare(&a.0, &a.1, &b.0, &b.1));
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        self.sort();
+        let mut run = Run::create(&self.dir)?;
+        {
+            let mut w = io::BufWriter::new(&mut run.file);
+            for (key, data) in self.records.drain(..) {
+                let mut buf = [0; 8];
+                uint32::pack2(&mut buf, key.len() as u32, data.len() as u32);
+                w.write_all(&buf)?;
+                w.write_all(&key)?;
+                w.write_all(&data)?;
+            }
+            w.flush()?;
+        }
+        run.file.seek(io::SeekFrom::Start(0))?;
+        self.runs.push(run);
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Pass all the records to `write` in sorted order.
+    pub(crate) fn finish<F>(mut self, mut write: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<()>,
+    {
+        self.sort();
+        let mut sources: Vec<Source> = Vec::with_capacity(self.runs.len() + 1);
+        for run in self.runs.iter_mut() {
+            sources.push(Source::Run(io::BufReader::new(&mut run.file)));
+        }
+        // The buffered records were added last, so they go last to
+        // break ties in favour of earlier runs.
+        sources.push(Source::Memory(self.records.into_iter()));
+
+        let mut heads = Vec::with_capacity(sources.len());
+        for source in sources.iter_mut() {
+            heads.push(source.next()?);
+        }
+        let compare = &self.compare;
+        loop {
+            let mut min: Option<(usize, &Record)> = None;
+            for (i, head) in heads.iter().enumerate() {
+                if let Some(record) = head {
+                    let lower = match min {
+                        None => true,
+                        Some((_, m)) => compare(&record.0, &record.1, &m.0, &m.1) == Ordering::Less,
+                    };
+                    if lower {
+                        min = Some((i, record));
+                    }
+                }
+            }
+            let m = match min {
+                None => return Ok(()),
+                Some((m, _)) => m,
+            };
+            let (key, data) = heads[m].take().unwrap();
+            write(&key, &data)?;
+            heads[m] = sources[m].next()?;
+        }
+    }
+}
+
+enum Source<'a> {
+    Run(io::BufReader<&'a mut fs::File>),
+    Memory(vec::IntoIter<Record>),
+}
+
+impl<'a> Source<'a> {
+    fn next(&mut self) -> Result<Option<Record>> {
+        match self {
+            Source::Memory(records) => Ok(records.next()),
+            Source::Run(r) => {
+                let mut buf = [0; 8];
+                if r.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+                r.read_exact(&mut buf)?;
+                let (klen, dlen) = uint32::unpack2(&buf);
+                let mut key = vec![0; klen as usize];
+                let mut data = vec![0; dlen as usize];
+                r.read_exact(&mut key)?;
+                r.read_exact(&mut data)?;
+                Ok(Some((key, data)))
+            }
+        }
+    }
+}
+
+/// A temporary file holding one sorted run, removed when dropped.
+struct Run {
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl Run {
+    fn create(dir: &std::path::Path) -> Result<Run> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        loop {
+            let n = COUNTER.fetch_add(1, AtomicOrdering::Relaxed);
+            let path = dir.join(format!(".cdb-sort.{}.{}", process::id(), n));
+            match fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(file) => return Ok(Run { path, file }),
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
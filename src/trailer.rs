@@ -0,0 +1,94 @@
+//! Optional sections appended to a CDB file after its hash tables.
+//!
+//! Standard CDB readers locate everything through the header, so they
+//! never look past the last hash table and ignore anything there. The
+//! trailer uses that space to hold extra sections, followed by a
+//! directory of `(tag, position, length)` entries, the number of
+//! entries and a magic number:
+//!
+//! ```text
+//! section data ... | tag pos len | ... | count | "CDB+"
+//! ```
+
+use std::io;
+use std::ops::Range;
+
+use crate::uint32;
+
+pub use std::io::Result;
+
+/// Identifies the contents of a section.
+pub(crate) type Tag = [u8; 4];
+
+/// A Bloom filter over the key hashes.
+pub(crate) const BLOOM: Tag = *b"blom";
+
+/// A minimal perfect hash index, replacing the standard hash tables.
+pub(crate) const PERFECT_HASH: Tag = *b"mphf";
+
+/// The positions of the records, sorted by key.
+pub(crate) const KEY_INDEX: Tag = *b"keys";
+
+/// The name of the normalizer applied to keys.
+pub(crate) const NORMALIZER: Tag = *b"norm";
+
+const MAGIC: &[u8; 4] = b"CDB+";
+
+fn err_badtrailer<T>() -> Result<T> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Invalid trailer format",
+    ))
+}
+
+/// Encode the directory and tail of a trailer whose sections have
+/// already been written at the given positions.
+pub(crate) fn directory(sections: &[(Tag, u32, u32)]) -> Vec<u8> {
+    let mut buf = vec![0; sections.len() * 12 + 8];
+    for ((tag, pos, len), entry) in sections.iter().zip(buf.chunks_mut(12)) {
+        entry[0..4].copy_from_slice(tag);
+        uint32::pack2(&mut entry[4..12], *pos, *len);
+    }
+    let tail = buf.len() - 8;
+    uint32::pack(&mut buf[tail..], sections.len() as u32);
+    buf[tail + 4..].copy_from_slice(MAGIC);
+    buf
+}
+
+/// The end of the hash tables, where any trailer starts.
+fn tables_end(map: &[u8]) -> u64 {
+    let mut end = uint32::unpack(&map[0..4]) as u64;
+    for entry in map[..2048].chunks(8) {
+        let (pos, slots) = uint32::unpack2(entry);
+        end = end.max(pos as u64 + slots as u64 * 8);
+    }
+    end
+}
+
+/// Find the sections in the trailer of a mapped file, which has
+/// already been checked to hold a complete header.
+pub(crate) fn parse(map: &[u8]) -> Result<Vec<(Tag, Range<usize>)>> {
+    let start = tables_end(map);
+    let size = map.len() as u64;
+    if start + 8 > size || &map[map.len() - 4..] != MAGIC {
+        return Ok(Vec::new());
+    }
+    let count = uint32::unpack(&map[map.len() - 8..]) as u64;
+    let dir = match (size - 8).checked_sub(count * 12) {
+        Some(dir) if dir >= start => dir as usize,
+        _ => return err_badtrailer(),
+    };
+    map[dir..map.len() - 8]
+        .chunks(12)
+        .map(|entry| {
+            let (pos, len) = uint32::unpack2(&entry[4..12]);
+            let end = pos as u64 + len as u64;
+            if (pos as u64) < start || end > dir as u64 {
+                return err_badtrailer();
+            }
+            let mut tag = [0; 4];
+            tag.copy_from_slice(&entry[0..4]);
+            Ok((tag, pos as usize..end as usize))
+        })
+        .collect()
+}
@@ -1,5 +1,3 @@
-use std::u32;
-
 pub fn unpack(data: &[u8]) -> u32 {
     assert!(data.len() >= 4);
     // Use u32::from_bytes when it stabilizes
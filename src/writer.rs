@@ -1,30 +1,87 @@
-use std::cmp::max;
+use std::cmp::{max, Ordering};
+use std::collections::hash_map::RandomState;
+use std::error;
+use std::ffi;
+use std::fmt;
 use std::fs;
+use std::hash::{BuildHasher, Hasher};
 use std::io;
 use std::io::prelude::*;
-use std::iter;
+use std::mem;
 use std::path;
-use std::string;
+use std::process;
+use std::sync::Arc;
+use std::time::{self, Duration, Instant};
 
-use crate::hash::hash;
+use crate::bloom;
+#[cfg(unix)]
+use crate::filesystem::{create_new_at, remove_at};
+use crate::filesystem::{lock_file, Filesystem, OsFilesystem};
+#[cfg(unix)]
+use crate::generations;
+use crate::hash::{hash, hash64};
+use crate::mmap::Mmap;
+use crate::normalize::{self, Normalizer};
+use crate::perfect;
+use crate::reader::CDB;
+use crate::sort::Sorter;
+use crate::trailer::{self, Tag};
 use crate::uint32;
 
 pub use std::io::Result;
 
 #[derive(Clone, Copy, Debug)]
-struct HashPos {
-    hash: u32,
-    pos: u32,
+pub(crate) struct HashPos {
+    pub(crate) hash: u32,
+    pub(crate) pos: u32,
 }
 
 impl HashPos {
-    fn pack(&self, buf: &mut [u8]) {
+    pub(crate) fn pack(&self, buf: &mut [u8]) {
         uint32::pack2(buf, self.hash, self.pos);
     }
 }
 
-fn err_toobig<T>() -> Result<T> {
-    Err(io::Error::new(io::ErrorKind::Other, "File too big"))
+pub(crate) fn err_toobig<T>() -> Result<T> {
+    Err(io::Error::other("File too big"))
+}
+
+fn err_shortdata<T>() -> Result<T> {
+    Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "Data ended before its declared length",
+    ))
+}
+
+/// The number of slots in the hash table for a bucket with `entries`
+/// entries. There is always at least one empty slot, so probing for a
+/// missing key terminates.
+fn table_len(entries: usize, slots_per_entry: f64) -> usize {
+    if entries == 0 {
+        0
+    } else {
+        max(
+            (entries as f64 * slots_per_entry).ceil() as usize,
+            entries + 1,
+        )
+    }
+}
+
+/// Place the entries for one header bucket into its hash table, using
+/// linear probing from each entry's starting slot. The table must be
+/// empty and larger than the number of entries.
+pub(crate) fn fill_table(entries: &[HashPos], table: &mut [HashPos]) {
+    let len = table.len();
+    for e in entries {
+        let mut wh = (e.hash as usize >> 8) % len;
+        while table[wh].pos != 0 {
+            wh += 1;
+            if wh == len {
+                wh = 0;
+            }
+        }
+        table[wh] = *e;
+    }
 }
 
 /// Base interface for making a CDB file.
@@ -45,6 +102,103 @@ pub struct CDBMake {
     entries: Vec<Vec<HashPos>>,
     pos: u32,
     file: io::BufWriter<fs::File>,
+    sorter: Option<Sorter>,
+    unique: Option<Unique>,
+    /// The error that left a partial record in the file, after which
+    /// no more records may be added.
+    poisoned: Option<io::Error>,
+    observer: Option<Box<dyn BuildObserver>>,
+    slots_per_entry: f64,
+    perfect_hash: bool,
+    key_index: bool,
+    bloom_bits: Option<u32>,
+    normalizer: Option<Arc<dyn Normalizer>>,
+    added: u64,
+    added_bytes: u64,
+    started: Instant,
+}
+
+/// How a [`CDBMake`](struct.CDBMake.html) in unique key mode handles a
+/// record whose key has already been added.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Duplicates {
+    /// Fail with a [`DuplicateKey`](struct.DuplicateKey.html) error.
+    Error,
+    /// Keep the first value and ignore later ones.
+    KeepFirst,
+    /// Replace earlier values with the last one.
+    KeepLast,
+}
+
+/// The error returned when a duplicate key is added with
+/// [`Duplicates::Error`](enum.Duplicates.html#variant.Error).
+///
+/// This is wrapped in an `io::Error` of kind `AlreadyExists`, and can
+/// be retrieved with `get_ref` and `downcast_ref`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DuplicateKey {
+    pub key: Vec<u8>,
+}
+
+impl fmt::Display for DuplicateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Duplicate key {:?}", String::from_utf8_lossy(&self.key))
+    }
+}
+
+impl error::Error for DuplicateKey {}
+
+struct Unique {
+    policy: Duplicates,
+    /// For each header bucket, an open addressing table of indexes
+    /// into its entries, plus one, by their hashes.
+    slots: Vec<Vec<u32>>,
+    /// The positions of records replaced with `Duplicates::KeepLast`,
+    /// which are removed from the file when it is finished.
+    superseded: Vec<u32>,
+}
+
+impl Unique {
+    /// The indexes of the entries of a bucket with the given hash.
+    fn candidates<'a>(
+        &'a self,
+        entries: &'a [HashPos],
+        khash: u32,
+    ) -> impl Iterator<Item = usize> + 'a {
+        let slots = &self.slots[(khash & 0xff) as usize];
+        let mask = slots.len().wrapping_sub(1);
+        let start = (khash >> 8) as usize;
+        (0..slots.len())
+            .map(move |i| slots[(start + i) & mask])
+            .take_while(|&slot| slot != 0)
+            .map(|slot| slot as usize - 1)
+            .filter(move |&i| entries[i].hash == khash)
+    }
+
+    /// Index the last entry added to a bucket.
+    fn insert(&mut self, entries: &[HashPos]) {
+        let khash = entries[entries.len() - 1].hash;
+        let slots = &mut self.slots[(khash & 0xff) as usize];
+        // Keep the table at most half full.
+        if entries.len() * 2 > slots.len() {
+            *slots = vec![0; (slots.len() * 2).max(8)];
+            for (i, entry) in entries.iter().enumerate() {
+                place_slot(slots, entry.hash, i);
+            }
+        } else {
+            place_slot(slots, khash, entries.len() - 1);
+        }
+    }
+}
+
+/// Place an entry index in the first free slot of a table for its hash.
+fn place_slot(slots: &mut [u32], khash: u32, i: usize) {
+    let mask = slots.len() - 1;
+    let mut x = (khash >> 8) as usize & mask;
+    while slots[x] != 0 {
+        x = (x + 1) & mask;
+    }
+    slots[x] = i as u32 + 1;
 }
 
 impl CDBMake {
@@ -53,16 +207,314 @@ impl CDBMake {
         let mut w = io::BufWriter::new(file);
         let buf = [0; 2048];
         w.seek(io::SeekFrom::Start(0))?;
-        w.write(&buf)?;
+        w.write_all(&buf)?;
         Ok(CDBMake {
-            entries: iter::repeat(vec![]).take(256).collect::<Vec<_>>(),
+            entries: vec![vec![]; 256],
             pos: 2048,
             file: w,
+            sorter: None,
+            unique: None,
+            poisoned: None,
+            observer: None,
+            slots_per_entry: 2.0,
+            perfect_hash: false,
+            key_index: false,
+            bloom_bits: None,
+            normalizer: None,
+            added: 0,
+            added_bytes: 0,
+            started: Instant::now(),
         })
     }
 
+    /// Require every key to be unique, handling duplicates according
+    /// to `policy`.
+    ///
+    /// Duplicates are found by reading back and comparing against the
+    /// keys already written, so the file must have been opened for
+    /// reading as well as writing, or else an error of kind
+    /// `InvalidInput` is returned. With
+    /// [`Duplicates::KeepLast`](enum.Duplicates.html),
+    /// superseded records are removed from the file when it is
+    /// finished, by moving the records after them back, so every key
+    /// is written only once.
+    ///
+    /// When records are [sorted](#method.sort), they are only written,
+    /// and so checked for duplicates, by [`finish`](#method.finish).
+    /// With [`Duplicates::Error`](enum.Duplicates.html#variant.Error),
+    /// a duplicate is then reported by `finish` after all the records
+    /// have been added, rather than by the `add` which added it.
+    ///
+    /// This must be called before any records are added.
+    pub fn unique_keys(&mut self, policy: Duplicates) -> Result<()> {
+        if !self.is_empty() {
+            return Err(io::Error::other("Records have already been added"));
+        }
+        self.check_readable("check for unique keys")?;
+        self.unique = Some(Unique {
+            policy,
+            slots: vec![vec![]; 256],
+            superseded: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// Write the records sorted by key and then by value, instead of
+    /// in the order they were added.
+    ///
+    /// This makes the file contents depend only on the set of records
+    /// added, regardless of the order they were added in. Records are
+    /// buffered until [`finish`](#method.finish), spilling sorted runs
+    /// to temporary files if they exceed the memory limit set with
+    /// [`set_sort_memory`](#method.set_sort_memory).
+    ///
+    /// This must be called before any records are added.
+    pub fn sort(&mut self) -> Result<()> {
+        self.sort_by(|key1, value1, key2, value2| (key1, value1).cmp(&(key2, value2)))
+    }
+
+    /// Write the records sorted with a comparison function, called as
+    /// `compare(key1, value1, key2, value2)`, instead of in the order
+    /// they were added.
+    ///
+    /// Records which compare as equal are written in the order they
+    /// were added, so the comparison must distinguish all records for
+    /// the output to be independent of that order.
+    ///
+    /// This must be called before any records are added.
+    pub fn sort_by<F>(&mut self, compare: F) -> Result<()>
+    where
+        F: Fn(&[u8], &[u8], &[u8], &[u8]) -> Ordering + Send + 'static,
+    {
+        if !self.is_empty() {
+            return Err(io::Error::other("Records have already been added"));
+        }
+        self.sorter = Some(Sorter::new(Box::new(compare)));
+        Ok(())
+    }
+
+    /// Set the approximate amount of memory used to buffer records
+    /// while sorting, before they are spilled to a temporary file. The
+    /// default is 64MiB.
+    pub fn set_sort_memory(&mut self, bytes: usize) {
+        if let Some(sorter) = &mut self.sorter {
+            sorter.set_memory(bytes);
+        }
+    }
+
+    /// Set the directory where temporary files are created while
+    /// sorting. The default is the system temporary directory.
+    pub fn set_sort_dir<P: Into<path::PathBuf>>(&mut self, dir: P) {
+        if let Some(sorter) = &mut self.sorter {
+            sorter.set_dir(dir.into());
+        }
+    }
+
+    /// Set an observer to be notified of progress while building the
+    /// file.
+    pub fn set_observer<O: BuildObserver + 'static>(&mut self, observer: O) {
+        self.observer = Some(Box::new(observer));
+    }
+
+    /// Set the number of hash table slots allocated for each record,
+    /// which must be greater than 1. The default is 2.
+    ///
+    /// More slots make lookups faster, particularly for missing keys,
+    /// which probe until they reach an empty slot, at the cost of 8
+    /// bytes per slot in the file. The file is a standard CDB file
+    /// whatever the setting.
+    pub fn set_slots_per_entry(&mut self, ratio: f64) -> Result<()> {
+        if !(ratio > 1.0 && ratio.is_finite()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Slots per entry must be greater than 1",
+            ));
+        }
+        self.slots_per_entry = ratio;
+        Ok(())
+    }
+
+    /// Write a minimal perfect hash index in place of the standard hash
+    /// tables, if every key is unique.
+    ///
+    /// The index needs one 4 byte slot for each record, plus 1 byte per
+    /// record on average, instead of 16 bytes per record for the hash
+    /// tables, and every key present is found by reading exactly one
+    /// slot.
+    ///
+    /// # Compatibility
+    ///
+    /// **The file is no longer a standard CDB file.** All 256 hash
+    /// tables are written empty, so any reader other than
+    /// [`CDB`](struct.CDB.html), including `cdbget` and other CDB
+    /// libraries, finds no records at all for any key, and sees the
+    /// database as empty unless it iterates over the records directly.
+    /// Only enable this for files read exclusively by this crate.
+    ///
+    /// The keys are read back from the file to build the index, so the
+    /// file must have been opened for reading as well as writing. If any
+    /// key occurs more than once, standard hash tables are written
+    /// instead, which is reported by
+    /// [`BuildStats::perfect_hash`](struct.BuildStats.html#structfield.perfect_hash).
+    /// Use [`unique_keys`](#method.unique_keys) to ensure keys are unique.
+    ///
+    /// Enabling this fails with an error of kind `InvalidInput` if the
+    /// file cannot be read.
+    pub fn set_perfect_hash(&mut self, perfect: bool) -> Result<()> {
+        if perfect {
+            self.check_readable("build a perfect hash index")?;
+        }
+        self.perfect_hash = perfect;
+        Ok(())
+    }
+
+    /// Fail unless the file was opened for reading, which is needed by
+    /// options that read back the records already written.
+    fn check_readable(&self, purpose: &str) -> Result<()> {
+        if self.file.get_ref().read(&mut []).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("File must be opened for reading to {}", purpose),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Keep a sorted index of the keys in the file, to support
+    /// [`CDB::prefix`](struct.CDB.html#method.prefix) and
+    /// [`CDB::range`](struct.CDB.html#method.range).
+    ///
+    /// The index holds the position of every record that can be found
+    /// by its key, sorted by key and then by position, taking 4 bytes
+    /// per record. It is stored after the hash tables, where it is
+    /// ignored by other CDB readers. The keys are read back from the
+    /// file to sort them, so the file must have been opened for
+    /// reading as well as writing. Enabling this fails with an error
+    /// of kind `InvalidInput` if the file cannot be read.
+    pub fn set_key_index(&mut self, index: bool) -> Result<()> {
+        if index {
+            self.check_readable("build a key index")?;
+        }
+        self.key_index = index;
+        Ok(())
+    }
+
+    /// Build the requested perfect hash index and sorted key index from
+    /// the records written so far, adding them to the trailer sections.
+    /// Returns whether a perfect hash index was built.
+    fn build_indexes(&mut self, sections: &mut Vec<(Tag, Vec<u8>)>) -> Result<bool> {
+        if !self.perfect_hash && !self.key_index {
+            return Ok(false);
+        }
+        let positions: Vec<u32> = self.entries.iter().flatten().map(|e| e.pos).collect();
+        if positions.is_empty() {
+            if self.key_index {
+                sections.push((trailer::KEY_INDEX, Vec::new()));
+            }
+            return Ok(false);
+        }
+        self.file.flush()?;
+        let map = Mmap::map(self.file.get_ref())?;
+        self.file.seek(io::SeekFrom::Start(self.pos as u64))?;
+        let mut perfect_hash = false;
+        if self.perfect_hash {
+            if let Some(perfect) = build_perfect_hash(&map, &self.normalizer, &positions) {
+                sections.push((trailer::PERFECT_HASH, perfect));
+                perfect_hash = true;
+            }
+        }
+        if self.key_index {
+            sections.push((
+                trailer::KEY_INDEX,
+                build_key_index(&map, &self.normalizer, positions),
+            ));
+        }
+        Ok(perfect_hash)
+    }
+
+    /// Normalize keys with `normalizer` before hashing and comparing
+    /// them, so lookups find keys by their normalized form.
+    ///
+    /// Keys are stored as they are added, and the name of the
+    /// normalizer is recorded after the hash tables, where it is
+    /// ignored by other CDB readers. Unique keys, the perfect hash
+    /// index and the sorted key index all use the normalized keys.
+    ///
+    /// This must be called before any records are added.
+    pub fn set_normalizer<N: Normalizer + 'static>(&mut self, normalizer: N) -> Result<()> {
+        if !self.is_empty() {
+            return Err(io::Error::other("Records have already been added"));
+        }
+        self.normalizer = Some(Arc::new(normalizer));
+        Ok(())
+    }
+
+    /// Add a Bloom filter of the keys to the file, using approximately
+    /// `bits_per_key` bits for each record, which must be from 1 to 64.
+    ///
+    /// The filter is stored after the hash tables, where it is ignored
+    /// by other CDB readers. [`CDB`](struct.CDB.html) checks it before
+    /// probing the hash tables, so most lookups of missing keys never
+    /// touch them. Ten bits per key give about a 1% false positive
+    /// rate.
+    pub fn set_bloom_filter(&mut self, bits_per_key: u32) -> Result<()> {
+        if bits_per_key == 0 || bits_per_key > bloom::MAX_BITS_PER_KEY {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Bits per key must be from 1 to 64",
+            ));
+        }
+        self.bloom_bits = Some(bits_per_key);
+        Ok(())
+    }
+
+    /// Count a record as added and notify the observer.
+    fn record_added(&mut self, keylen: usize, datalen: u64) {
+        self.added += 1;
+        self.added_bytes += 8 + keylen as u64 + datalen;
+        if let Some(observer) = &mut self.observer {
+            observer.added(self.added, self.added_bytes);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == 2048
+            && self
+                .sorter
+                .as_ref()
+                .map_or(true, |sorter| sorter.is_empty())
+    }
+
+    /// Fail if an earlier write error left the file unusable.
+    fn check_poisoned(&self) -> Result<()> {
+        match &self.poisoned {
+            Some(err) => Err(io::Error::new(err.kind(), err.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Remember an error which may have left a partial record in the
+    /// file, so that no more data is written after it.
+    fn poison<T>(&mut self, result: Result<T>) -> Result<T> {
+        if let Err(err) = &result {
+            self.poisoned
+                .get_or_insert_with(|| io::Error::new(err.kind(), err.to_string()));
+        }
+        result
+    }
+
+    /// Fail before writing anything if a record would not fit in the
+    /// file.
+    fn check_fits(&self, keylen: usize, datalen: u64) -> Result<()> {
+        if self.pos as u64 + 8 + keylen as u64 + datalen > 0xffffffff {
+            err_toobig()
+        } else {
+            Ok(())
+        }
+    }
+
     fn pos_plus(&mut self, len: u32) -> Result<()> {
-        if self.pos + len < len {
+        if self.pos.checked_add(len).is_none() {
             err_toobig()
         } else {
             self.pos += len;
@@ -70,11 +522,30 @@ impl CDBMake {
         }
     }
 
-    fn add_end(&mut self, keylen: u32, datalen: u32, hash: u32) -> Result<()> {
-        self.entries[(hash & 0xff) as usize].push(HashPos {
-            hash: hash,
+    fn add_end(
+        &mut self,
+        keylen: u32,
+        datalen: u32,
+        hash: u32,
+        replace: Option<usize>,
+    ) -> Result<()> {
+        let entries = &mut self.entries[(hash & 0xff) as usize];
+        let entry = HashPos {
+            hash,
             pos: self.pos,
-        });
+        };
+        match (replace, &mut self.unique) {
+            (Some(i), Some(unique)) => {
+                unique.superseded.push(entries[i].pos);
+                entries[i] = entry;
+            }
+            (_, unique) => {
+                entries.push(entry);
+                if let Some(unique) = unique {
+                    unique.insert(entries);
+                }
+            }
+        }
         self.pos_plus(8)?;
         self.pos_plus(keylen)?;
         self.pos_plus(datalen)?;
@@ -84,19 +555,164 @@ impl CDBMake {
     fn add_begin(&mut self, keylen: u32, datalen: u32) -> Result<()> {
         let mut buf = [0; 8];
         uint32::pack2(&mut buf[0..8], keylen, datalen);
-        self.file.write(&buf)?;
+        self.file.write_all(&buf)?;
         Ok(())
     }
 
     /// Add a record to the CDB file.
+    ///
+    /// If writing the record fails, it may be left partially written,
+    /// so all further calls to add records or finish the file fail
+    /// with the same error.
     pub fn add(&mut self, key: &[u8], data: &[u8]) -> Result<()> {
+        self.check_poisoned()?;
         if key.len() >= 0xffffffff || data.len() >= 0xffffffff {
-            return Err(io::Error::new(io::ErrorKind::Other, "Key or data too big"));
+            return Err(io::Error::other("Key or data too big"));
+        }
+        match &mut self.sorter {
+            Some(sorter) => {
+                // A failed spill loses the records being written out.
+                let result = sorter.add(key.to_vec(), data.to_vec());
+                self.poison(result)?;
+            }
+            None => {
+                if !self.write_record(key, data)? {
+                    return Ok(());
+                }
+            }
         }
-        self.add_begin(key.len() as u32, data.len() as u32)?;
-        self.file.write(key)?;
-        self.file.write(data)?;
-        self.add_end(key.len() as u32, data.len() as u32, hash(&key[..]))
+        self.record_added(key.len(), data.len() as u64);
+        Ok(())
+    }
+
+    /// Write a record into the file, returning `false` if it was
+    /// skipped as a duplicate key.
+    fn write_record(&mut self, key: &[u8], data: &[u8]) -> Result<bool> {
+        self.check_fits(key.len(), data.len() as u64)?;
+        let normalized = normalize::apply(&self.normalizer, key);
+        let khash = hash(&normalized);
+        let replace = match self.check_unique(key, &normalized, khash)? {
+            Some(replace) => replace,
+            None => return Ok(false),
+        };
+        let result = self
+            .add_begin(key.len() as u32, data.len() as u32)
+            .and_then(|()| self.file.write_all(key))
+            .and_then(|()| self.file.write_all(data));
+        self.poison(result)?;
+        let result = self.add_end(key.len() as u32, data.len() as u32, khash, replace);
+        self.poison(result)?;
+        Ok(true)
+    }
+
+    /// Check a new key against the unique key policy. Returns `None` if
+    /// the record should be skipped, or else the index of the entry it
+    /// replaces, if any.
+    fn check_unique(
+        &mut self,
+        key: &[u8],
+        normalized: &[u8],
+        khash: u32,
+    ) -> Result<Option<Option<usize>>> {
+        let unique = match &self.unique {
+            None => return Ok(Some(None)),
+            Some(unique) => unique,
+        };
+        let entries = &self.entries[(khash & 0xff) as usize];
+        for i in unique.candidates(entries, khash) {
+            if self.match_key(normalized, entries[i].pos)? {
+                return match unique.policy {
+                    Duplicates::Error => Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        DuplicateKey { key: key.to_vec() },
+                    )),
+                    Duplicates::KeepFirst => Ok(None),
+                    Duplicates::KeepLast => Ok(Some(Some(i))),
+                };
+            }
+        }
+        Ok(Some(None))
+    }
+
+    /// Compare a normalized key against the key of the record already
+    /// written at `pos`.
+    fn match_key(&self, key: &[u8], pos: u32) -> Result<bool> {
+        let mut buf = [0; 8];
+        self.read_written(&mut buf, pos)?;
+        let (klen, _) = uint32::unpack2(&buf);
+        if self.normalizer.is_none() && klen as usize != key.len() {
+            return Ok(false);
+        }
+        let mut written = vec![0; klen as usize];
+        self.read_written(&mut written, pos + 8)?;
+        Ok(*normalize::apply(&self.normalizer, &written) == *key)
+    }
+
+    /// Read back data already written at `pos`, part of which may
+    /// still be in the write buffer, without moving the write position.
+    fn read_written(&self, buf: &mut [u8], pos: u32) -> Result<()> {
+        let buffered = self.file.buffer();
+        let flushed = self.pos as usize - buffered.len();
+        let (pos, end) = (pos as usize, pos as usize + buf.len());
+        let split = flushed.clamp(pos, end) - pos;
+        let (in_file, in_buffer) = buf.split_at_mut(split);
+        read_at(self.file.get_ref(), in_file, pos as u64)?;
+        if !in_buffer.is_empty() {
+            let start = pos + split - flushed;
+            in_buffer.copy_from_slice(&buffered[start..start + in_buffer.len()]);
+        }
+        Ok(())
+    }
+
+    /// Add a record to the CDB file, streaming exactly `datalen` bytes
+    /// of its value from `data` instead of requiring it in memory.
+    ///
+    /// If `data` fails or ends before `datalen` bytes have been read,
+    /// an error is returned and, as the record has been partially
+    /// written, no more records may be added.
+    ///
+    /// When sorting records, the value is read into memory like any
+    /// other record, and a short read leaves the maker usable.
+    pub fn add_reader<R: Read>(&mut self, key: &[u8], datalen: u64, data: R) -> Result<()> {
+        self.check_poisoned()?;
+        if key.len() >= 0xffffffff || datalen >= 0xffffffff {
+            return Err(io::Error::other("Key or data too big"));
+        }
+        if let Some(sorter) = &mut self.sorter {
+            // The length is only what the caller claims, so let the
+            // buffer grow with the data actually read.
+            let mut value = Vec::new();
+            if data.take(datalen).read_to_end(&mut value)? < datalen as usize {
+                return err_shortdata();
+            }
+            let result = sorter.add(key.to_vec(), value);
+            self.poison(result)?;
+            self.record_added(key.len(), datalen);
+            return Ok(());
+        }
+        self.check_fits(key.len(), datalen)?;
+        let normalized = normalize::apply(&self.normalizer, key);
+        let khash = hash(&normalized);
+        let replace = match self.check_unique(key, &normalized, khash)? {
+            Some(replace) => replace,
+            None => return Ok(()),
+        };
+        let result = self
+            .add_begin(key.len() as u32, datalen as u32)
+            .and_then(|()| self.file.write_all(key))
+            .and_then(|()| io::copy(&mut data.take(datalen), &mut self.file))
+            .and_then(|copied| {
+                if copied < datalen {
+                    err_shortdata()
+                } else {
+                    Ok(())
+                }
+            });
+        self.poison(result)?;
+        let result = self.add_end(key.len() as u32, datalen as u32, khash, replace);
+        self.poison(result)?;
+        self.record_added(key.len(), datalen);
+        Ok(())
     }
 
     /// Set the permissions on the underlying file.
@@ -104,11 +720,132 @@ impl CDBMake {
         self.file.get_ref().set_permissions(perm)
     }
 
-    /// Finish writing to the CDB file and flush its contents.
-    pub fn finish(mut self) -> Result<()> {
-        let mut buf = [0; 8];
+    /// Write the trailer sections after the hash tables.
+    fn write_trailer(&mut self, sections: &[(Tag, Vec<u8>)]) -> Result<()> {
+        if sections.is_empty() {
+            return Ok(());
+        }
+        let mut directory = Vec::with_capacity(sections.len());
+        for (tag, data) in sections {
+            if data.len() > 0xffffffff {
+                return err_toobig();
+            }
+            directory.push((*tag, self.pos, data.len() as u32));
+            self.file.write_all(data)?;
+            self.pos_plus(data.len() as u32)?;
+        }
+        let directory = trailer::directory(&directory);
+        self.file.write_all(&directory)?;
+        self.pos_plus(directory.len() as u32)
+    }
+
+    /// Remove the records at `positions` from the file, moving the
+    /// records after each of them back to close the gap.
+    fn remove_records(&mut self, mut positions: Vec<u32>) -> Result<()> {
+        positions.sort_unstable();
+        self.file.flush()?;
+        let file = self.file.get_ref();
+        // The ranges of records to keep, and how far each moves back.
+        let mut moves = Vec::with_capacity(positions.len() + 1);
+        let mut start = 2048;
+        let mut removed = 0;
+        for pos in positions {
+            moves.push((start..pos, removed));
+            let mut buf = [0; 8];
+            read_at(file, &mut buf, pos as u64)?;
+            let (klen, dlen) = uint32::unpack2(&buf);
+            start = pos + 8 + klen + dlen;
+            removed += start - pos;
+        }
+        moves.push((start..self.pos, removed));
+
+        let mut buf = vec![0; 1 << 16];
+        for (range, shift) in &moves {
+            let mut pos = range.start;
+            while pos < range.end {
+                let n = buf.len().min((range.end - pos) as usize);
+                read_at(file, &mut buf[..n], pos as u64)?;
+                write_at(file, &buf[..n], (pos - shift) as u64)?;
+                pos += n as u32;
+            }
+        }
+        for entry in self.entries.iter_mut().flatten() {
+            let i = moves.partition_point(|(range, _)| range.start <= entry.pos);
+            entry.pos -= moves[i - 1].1;
+        }
+        self.pos -= removed;
+        self.file.seek(io::SeekFrom::Start(self.pos as u64))?;
+        Ok(())
+    }
+
+    /// Finish writing to the CDB file and flush its contents,
+    /// returning statistics about the build.
+    pub fn finish(self) -> Result<BuildStats> {
+        self.finish_file().map(|(_, stats)| stats)
+    }
+
+    /// Finish writing, returning the underlying file and statistics
+    /// about the build.
+    fn finish_file(mut self) -> Result<(fs::File, BuildStats)> {
+        self.check_poisoned()?;
+        let adding = self.started.elapsed();
+        let sorting = match self.sorter.take() {
+            None => Duration::default(),
+            Some(sorter) => {
+                let start = Instant::now();
+                let (mut records, mut bytes) = (0, 0);
+                sorter.finish(|key, data| {
+                    if !self.write_record(key, data)? {
+                        return Ok(());
+                    }
+                    records += 1;
+                    bytes += 8 + key.len() as u64 + data.len() as u64;
+                    if let Some(observer) = &mut self.observer {
+                        observer.sorted(records, bytes);
+                    }
+                    Ok(())
+                })?;
+                start.elapsed()
+            }
+        };
+        let start = Instant::now();
+        let compact = match &mut self.unique {
+            Some(unique) if !unique.superseded.is_empty() => {
+                let superseded = mem::take(&mut unique.superseded);
+                self.remove_records(superseded)?;
+                true
+            }
+            _ => false,
+        };
+        let compacting = start.elapsed();
 
-        let maxsize = self.entries.iter().fold(1, |acc, e| max(acc, e.len() * 2));
+        let start = Instant::now();
+        let mut sections = Vec::new();
+        let perfect_hash = self.build_indexes(&mut sections)?;
+        if let Some(bits_per_key) = self.bloom_bits {
+            let khashes: Vec<u32> = self.entries.iter().flatten().map(|e| e.hash).collect();
+            sections.push((trailer::BLOOM, bloom::build(&khashes, bits_per_key)));
+        }
+        let indexing = start.elapsed();
+
+        let start = Instant::now();
+        let mut buf = [0; 8];
+        // The perfect hash index replaces the hash tables, which are
+        // left empty.
+        let slots_per_entry = self.slots_per_entry;
+        let table_len = |entries| {
+            if perfect_hash {
+                0
+            } else {
+                table_len(entries, slots_per_entry)
+            }
+        };
+        let largest = if perfect_hash {
+            0
+        } else {
+            self.entries.iter().fold(0, |acc, e| max(acc, e.len()))
+        };
+        let maxsize = table_len(largest);
         let count = self.entries.iter().fold(0, |acc, e| acc + e.len());
         if maxsize + count > (0xffffffff / 8) {
             return err_toobig();
@@ -116,46 +853,235 @@ impl CDBMake {
 
         let mut table = vec![HashPos { hash: 0, pos: 0 }; maxsize];
 
-        let mut header = [0 as u8; 2048];
+        let mut header = [0u8; 2048];
         for i in 0..256 {
-            let len = self.entries[i].len() * 2;
+            let len = table_len(self.entries[i].len());
             let j = i * 8;
             uint32::pack2(&mut header[j..j + 8], self.pos, len as u32);
 
-            for e in self.entries[i].iter() {
-                let mut wh = (e.hash as usize >> 8) % len;
-                while table[wh].pos != 0 {
-                    wh += 1;
-                    if wh == len {
-                        wh = 0;
-                    }
-                }
-                table[wh] = *e;
+            if len > 0 {
+                fill_table(&self.entries[i], &mut table[..len]);
             }
 
             for hp in table.iter_mut().take(len) {
                 hp.pack(&mut buf);
-                self.file.write(&buf)?;
+                self.file.write_all(&buf)?;
                 self.pos_plus(8)?;
                 *hp = HashPos { hash: 0, pos: 0 };
             }
+            if let Some(observer) = &mut self.observer {
+                observer.table_written(i, self.pos as u64);
+            }
+        }
+
+        if let Some(normalizer) = &self.normalizer {
+            let name = normalizer.name().as_bytes().to_vec();
+            sections.push((trailer::NORMALIZER, name));
+        }
+        self.write_trailer(&sections)?;
+        if let Some(observer) = &mut self.observer {
+            observer.finished(self.pos as u64);
         }
 
         self.file.flush()?;
         self.file.seek(io::SeekFrom::Start(0))?;
-        self.file.write(&header)?;
+        self.file.write_all(&header)?;
         self.file.flush()?;
-        Ok(())
+        if compact {
+            // Drop what was left after the end of the compacted file.
+            self.file.get_ref().set_len(self.pos as u64)?;
+        }
+
+        let stats = BuildStats {
+            records: count as u32,
+            file_size: self.pos,
+            largest_bucket: largest as u32,
+            perfect_hash,
+            adding,
+            sorting,
+            compacting,
+            indexing,
+            tables: start.elapsed(),
+            publishing: Duration::default(),
+        };
+        let file = self.file.into_inner().map_err(|err| err.into_error())?;
+        Ok((file, stats))
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &fs::File, buf: &mut [u8], pos: u64) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, pos)
+}
+
+#[cfg(not(unix))]
+fn read_at(mut file: &fs::File, buf: &mut [u8], pos: u64) -> Result<()> {
+    let end = file.stream_position()?;
+    file.seek(io::SeekFrom::Start(pos))?;
+    let result = file.read_exact(buf);
+    file.seek(io::SeekFrom::Start(end))?;
+    result
+}
+
+#[cfg(unix)]
+fn write_at(file: &fs::File, buf: &[u8], pos: u64) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buf, pos)
+}
+
+#[cfg(not(unix))]
+fn write_at(mut file: &fs::File, buf: &[u8], pos: u64) -> Result<()> {
+    file.seek(io::SeekFrom::Start(pos))?;
+    file.write_all(buf)
+}
+
+/// The key of the record written at `pos`.
+fn record_key(map: &[u8], pos: u32) -> &[u8] {
+    let pos = pos as usize;
+    let (klen, _) = uint32::unpack2(&map[pos..pos + 8]);
+    &map[pos + 8..pos + 8 + klen as usize]
+}
+
+/// Build the perfect hash index for the records at `positions`, or
+/// return `None` if their keys cannot be separated by their hashes.
+fn build_perfect_hash(
+    map: &[u8],
+    normalizer: &Option<Arc<dyn Normalizer>>,
+    positions: &[u32],
+) -> Option<Vec<u8>> {
+    let mut keys: Vec<(u64, u32)> = positions
+        .iter()
+        .map(|&pos| {
+            let key = normalize::apply(normalizer, record_key(map, pos));
+            (hash64(&key), pos)
+        })
+        .collect();
+    // Equal hashes are either duplicate keys or a collision, neither
+    // of which can be told apart by the index.
+    keys.sort_unstable();
+    if keys.windows(2).any(|w| w[0].0 == w[1].0) {
+        return None;
+    }
+    perfect::build(keys)
+}
+
+/// Build the sorted key index for the records at `positions`.
+fn build_key_index(
+    map: &[u8],
+    normalizer: &Option<Arc<dyn Normalizer>>,
+    mut positions: Vec<u32>,
+) -> Vec<u8> {
+    match normalizer {
+        None => positions
+            .sort_unstable_by(|&a, &b| record_key(map, a).cmp(record_key(map, b)).then(a.cmp(&b))),
+        // Normalize each key once, rather than on every comparison.
+        Some(normalizer) => {
+            let mut keys: Vec<_> = positions
+                .iter()
+                .map(|&pos| (normalizer.normalize(record_key(map, pos)), pos))
+                .collect();
+            keys.sort_unstable();
+            positions = keys.into_iter().map(|(_, pos)| pos).collect();
+        }
+    }
+    let mut buf = vec![0; positions.len() * 4];
+    for (pos, out) in positions.iter().zip(buf.chunks_mut(4)) {
+        uint32::pack(out, *pos);
+    }
+    buf
+}
+
+/// Generate a random number for temporary file names, using the
+/// randomly keyed hasher from the standard library.
+pub(crate) fn random() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(process::id());
+    if let Ok(now) = time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    hasher.finish()
+}
+
+/// Statistics about building a CDB file, returned by
+/// [`CDBMake::finish`](struct.CDBMake.html#method.finish),
+/// [`CDBWriter::finish`](struct.CDBWriter.html#method.finish) and
+/// [`CDBWriter::finish_and_open`](struct.CDBWriter.html#method.finish_and_open).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BuildStats {
+    /// The number of records that can be found through the hash
+    /// tables.
+    pub records: u32,
+    /// The total size of the file.
+    pub file_size: u32,
+    /// The largest number of records in any one of the 256 hash tables.
+    pub largest_bucket: u32,
+    /// Whether the file was written with a perfect hash index instead
+    /// of hash tables.
+    pub perfect_hash: bool,
+    /// The time spent adding records, from creating the maker until
+    /// finishing it.
+    pub adding: Duration,
+    /// The time spent writing out sorted records while finishing, or
+    /// zero if the records were not sorted.
+    pub sorting: Duration,
+    /// The time spent removing records replaced under
+    /// [`Duplicates::KeepLast`](enum.Duplicates.html#variant.KeepLast),
+    /// or zero if none were.
+    pub compacting: Duration,
+    /// The time spent building the perfect hash index, sorted key index
+    /// and Bloom filter, or zero if none were enabled.
+    pub indexing: Duration,
+    /// The time spent writing the hash tables, any trailer and the
+    /// header.
+    pub tables: Duration,
+    /// The time spent syncing and renaming the file into place, or zero
+    /// for a [`CDBMake`](struct.CDBMake.html).
+    pub publishing: Duration,
+}
+
+impl BuildStats {
+    /// The total time spent building the file.
+    pub fn total(&self) -> Duration {
+        self.adding + self.sorting + self.compacting + self.indexing + self.tables + self.publishing
     }
 }
 
+/// Receives progress notifications while a CDB file is built.
+///
+/// All the methods do nothing by default, so an implementation need
+/// only override the ones it is interested in. They are called
+/// synchronously from the writer, so should return quickly.
+pub trait BuildObserver: Send {
+    /// A record was added, bringing the totals to `records` records and
+    /// `bytes` bytes of record data, including the record headers.
+    /// Records skipped as duplicate keys are not counted, except when
+    /// sorting, where duplicates are only found as the sorted records
+    /// are written out and are left out of [`sorted`](#method.sorted)
+    /// instead.
+    fn added(&mut self, _records: u64, _bytes: u64) {}
+
+    /// A sorted record was written out while finishing, bringing the
+    /// totals written to `records` records and `bytes` bytes.
+    fn sorted(&mut self, _records: u64, _bytes: u64) {}
+
+    /// Hash table number `table`, out of 256, was written while
+    /// finishing, bringing the file to `bytes` bytes.
+    fn table_written(&mut self, _table: usize, _bytes: u64) {}
+
+    /// The file was finished at `bytes` bytes, including the header,
+    /// hash tables and any trailer.
+    fn finished(&mut self, _bytes: u64) {}
+}
+
 /// A CDB file writer which handles atomic updating.
 ///
 /// Using this type, a CDB file is safely written by first creating a
 /// temporary file, building the CDB structure into that temporary file,
 /// and finally renaming that temporary file over the final file name.
 /// If the temporary file is not properly finished (ie due to an error),
-/// the temporary file is deleted when this writer is dropped.
+/// the temporary file is deleted when this writer is dropped, or
+/// explicitly with [`abort`](#method.abort).
 ///
 /// # Example
 ///
@@ -170,56 +1096,207 @@ impl CDBMake {
 /// }
 /// ```
 pub struct CDBWriter {
-    dstname: String,
-    tmpname: String,
+    dstname: path::PathBuf,
+    tmpname: path::PathBuf,
+    /// The directory the names are relative to, if created with
+    /// `create_at`.
+    dir: Option<fs::File>,
     cdb: Option<CDBMake>,
+    sync_file: bool,
+    sync_dir: bool,
+    preserve_attributes: bool,
+    generations: Option<usize>,
+    filesystem: Box<dyn Filesystem>,
+    lock: Option<fs::File>,
+    /// Whether the temporary file still needs to be removed.
+    cleanup: bool,
+}
+
+/// Append a suffix to a file name.
+fn with_name_suffix<S: AsRef<ffi::OsStr>>(filename: &path::Path, suffix: S) -> path::PathBuf {
+    let mut name = filename.as_os_str().to_os_string();
+    name.push(suffix);
+    name.into()
 }
 
 impl CDBWriter {
     /// Safely create a new CDB file.
     ///
     /// The suffix for the temporary file defaults to `".tmp"`.
-    pub fn create<P: AsRef<path::Path> + string::ToString>(filename: P) -> Result<CDBWriter> {
+    pub fn create<P: AsRef<path::Path>>(filename: P) -> Result<CDBWriter> {
         CDBWriter::with_suffix(filename, ".tmp")
     }
 
     /// Safely create a new CDB file, using a specific suffix for the temporary file.
-    pub fn with_suffix<P: AsRef<path::Path> + string::ToString>(
+    pub fn with_suffix<P: AsRef<path::Path>, S: AsRef<ffi::OsStr>>(
         filename: P,
-        suffix: &str,
+        suffix: S,
     ) -> Result<CDBWriter> {
-        let mut tmpname = filename.to_string();
-        tmpname.push_str(suffix);
-        CDBWriter::with_filenames(filename, &tmpname)
+        let tmpname = with_name_suffix(filename.as_ref(), suffix);
+        CDBWriter::with_filenames(filename, tmpname)
     }
 
     /// Safely create a new CDB file, using two specific file names.
     ///
     /// Note that the temporary file name must be on the same filesystem
     /// as the destination, or else the final rename will fail.
-    pub fn with_filenames<
-        P: AsRef<path::Path> + string::ToString,
-        Q: AsRef<path::Path> + string::ToString,
-    >(
+    pub fn with_filenames<P: AsRef<path::Path>, Q: AsRef<path::Path>>(
         filename: P,
         tmpname: Q,
     ) -> Result<CDBWriter> {
-        let file = fs::File::create(&tmpname)?;
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmpname)?;
+        CDBWriter::with_file(
+            filename.as_ref().into(),
+            tmpname.as_ref().into(),
+            None,
+            file,
+        )
+    }
+
+    fn with_file(
+        dstname: path::PathBuf,
+        tmpname: path::PathBuf,
+        dir: Option<fs::File>,
+        file: fs::File,
+    ) -> Result<CDBWriter> {
         let cdb = CDBMake::new(file)?;
         Ok(CDBWriter {
-            dstname: filename.to_string(),
-            tmpname: tmpname.to_string(),
+            dstname,
+            tmpname,
+            dir,
             cdb: Some(cdb),
+            sync_file: true,
+            sync_dir: true,
+            preserve_attributes: false,
+            generations: None,
+            filesystem: Box::new(OsFilesystem),
+            lock: None,
+            cleanup: true,
         })
     }
 
+    /// Safely create a new CDB file, using a unique temporary file.
+    ///
+    /// The temporary file is named with a random suffix and is created
+    /// exclusively, failing rather than truncating an existing file, so
+    /// concurrent writers for the same destination never share a
+    /// temporary file.
+    pub fn create_unique<P: AsRef<path::Path>>(filename: P) -> Result<CDBWriter> {
+        let filename = filename.as_ref();
+        loop {
+            let tmpname = with_name_suffix(filename, format!(".tmp.{:016x}", random()));
+            let file = match fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&tmpname)
+            {
+                Ok(file) => file,
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            };
+            return CDBWriter::with_file(filename.into(), tmpname, None, file);
+        }
+    }
+
+    /// Safely create a new CDB file within an open directory, using a
+    /// unique temporary file.
+    ///
+    /// The file name is resolved relative to `dir` with `openat` and
+    /// `renameat`, so the file is published into that directory even
+    /// if it is renamed or its path is replaced while writing. The
+    /// directory handle is also what gets synced. Writers created this
+    /// way do not support
+    /// [`set_preserve_attributes`](#method.set_preserve_attributes) or
+    /// [`set_generations`](#method.set_generations), which fail with an
+    /// error of kind `Unsupported`.
+    #[cfg(unix)]
+    pub fn create_at<P: AsRef<path::Path>>(dir: &fs::File, filename: P) -> Result<CDBWriter> {
+        let filename = filename.as_ref();
+        loop {
+            let tmpname = with_name_suffix(filename, format!(".tmp.{:016x}", random()));
+            let file = match create_new_at(dir, &tmpname) {
+                Ok(file) => file,
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            };
+            let dir = dir.try_clone()?;
+            return CDBWriter::with_file(filename.into(), tmpname, Some(dir), file);
+        }
+    }
+
+    /// Safely create a new CDB file, using a unique temporary file,
+    /// while holding an exclusive lock on the destination.
+    ///
+    /// The lock is an advisory lock on a separate file named with a
+    /// `".lock"` suffix, which is left in place afterwards. If another
+    /// writer holds the lock, this fails with an error of kind
+    /// `WouldBlock`. The lock is released when the writer is finished
+    /// or dropped.
+    pub fn create_locked<P: AsRef<path::Path>>(filename: P) -> Result<CDBWriter> {
+        let lock = lock_file(&with_name_suffix(filename.as_ref(), ".lock"))?;
+        let mut writer = CDBWriter::create_unique(filename)?;
+        writer.lock = Some(lock);
+        Ok(writer)
+    }
+
     /// Add a record to the CDB file.
+    ///
+    /// See [`CDBMake::add`](struct.CDBMake.html#method.add).
     pub fn add(&mut self, key: &[u8], data: &[u8]) -> Result<()> {
         // The unwrap() is safe here, as the internal cdb is only ever
-        // None during finish(), which does not call this.
+        // None during finish() or abort(), which do not call this.
         self.cdb.as_mut().unwrap().add(key, data)
     }
 
+    /// Add a record to the CDB file, streaming its value from a reader.
+    ///
+    /// See [`CDBMake::add_reader`](struct.CDBMake.html#method.add_reader).
+    pub fn add_reader<R: Read>(&mut self, key: &[u8], datalen: u64, data: R) -> Result<()> {
+        self.cdb.as_mut().unwrap().add_reader(key, datalen, data)
+    }
+
+    /// Write the records sorted by key and then by value.
+    ///
+    /// See [`CDBMake::sort`](struct.CDBMake.html#method.sort).
+    pub fn sort(&mut self) -> Result<()> {
+        self.cdb.as_mut().unwrap().sort()
+    }
+
+    /// Write the records sorted with a comparison function.
+    ///
+    /// See [`CDBMake::sort_by`](struct.CDBMake.html#method.sort_by).
+    pub fn sort_by<F>(&mut self, compare: F) -> Result<()>
+    where
+        F: Fn(&[u8], &[u8], &[u8], &[u8]) -> Ordering + Send + 'static,
+    {
+        self.cdb.as_mut().unwrap().sort_by(compare)
+    }
+
+    /// Set the approximate amount of memory used to buffer records
+    /// while sorting.
+    pub fn set_sort_memory(&mut self, bytes: usize) {
+        self.cdb.as_mut().unwrap().set_sort_memory(bytes)
+    }
+
+    /// Set the directory where temporary files are created while
+    /// sorting.
+    pub fn set_sort_dir<P: Into<path::PathBuf>>(&mut self, dir: P) {
+        self.cdb.as_mut().unwrap().set_sort_dir(dir)
+    }
+
+    /// Require every key to be unique.
+    ///
+    /// See [`CDBMake::unique_keys`](struct.CDBMake.html#method.unique_keys).
+    pub fn unique_keys(&mut self, policy: Duplicates) -> Result<()> {
+        self.cdb.as_mut().unwrap().unique_keys(policy)
+    }
+
     /// Set permissions on the temporary file.
     ///
     /// This must be done before the file is finished, as the temporary
@@ -228,18 +1305,377 @@ impl CDBWriter {
         self.cdb.as_ref().unwrap().set_permissions(perm)
     }
 
-    pub fn finish(mut self) -> Result<()> {
-        self.cdb.take().unwrap().finish()?;
-        fs::rename(&self.tmpname, &self.dstname)?;
+    /// Set whether the contents of the temporary file are synced to
+    /// stable storage before it is renamed. The default is `true`.
+    ///
+    /// Without this, a crash shortly after finishing may leave an empty
+    /// or partially written file under the final name.
+    pub fn set_sync_file(&mut self, sync: bool) {
+        self.sync_file = sync;
+    }
+
+    /// Set whether the directory containing the file is synced to
+    /// stable storage after the rename. The default is `true`.
+    ///
+    /// Without this, a crash shortly after finishing may lose the
+    /// rename, leaving the previous file in place.
+    pub fn set_sync_dir(&mut self, sync: bool) {
+        self.sync_dir = sync;
+    }
+
+    /// Set whether the permissions, ownership and extended attributes
+    /// of an existing destination file are copied onto the new file
+    /// before it replaces the old one. The default is `false`.
+    ///
+    /// Ownership and attributes that cannot be set without privileges
+    /// are silently skipped. Enabling this fails with an error of kind
+    /// `Unsupported` for writers created with
+    /// [`create_at`](#method.create_at).
+    pub fn set_preserve_attributes(&mut self, preserve: bool) -> Result<()> {
+        if preserve {
+            self.check_by_path()?;
+        }
+        self.preserve_attributes = preserve;
+        Ok(())
+    }
+
+    /// Fail if the writer was created in a directory handle, and so
+    /// cannot use options which work with the destination by path.
+    fn check_by_path(&self) -> Result<()> {
+        if self.dir.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Not supported for files created in a directory handle",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Publish the file as a new generation, keeping the newest `keep`
+    /// generations, which must be at least 1.
+    ///
+    /// Instead of being renamed over the destination, the finished file
+    /// is renamed to the destination name with a numbered suffix, as in
+    /// `data.cdb.7`, and the destination is atomically replaced by a
+    /// symbolic link to it. Readers opening the destination follow the
+    /// link as usual. Older generations beyond the newest `keep` are
+    /// removed, and [`rollback`](#method.rollback) can point the
+    /// destination back at an earlier one. An existing regular file at
+    /// the destination is kept as the oldest generation.
+    ///
+    /// Failing to remove old generations does not fail the publish, as
+    /// the new file is already in place; they are removed by a later
+    /// publish instead.
+    ///
+    /// This fails with an error of kind `Unsupported` for writers
+    /// created with [`create_at`](#method.create_at).
+    #[cfg(unix)]
+    pub fn set_generations(&mut self, keep: usize) -> Result<()> {
+        self.check_by_path()?;
+        if keep == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "At least one generation must be kept",
+            ));
+        }
+        self.generations = Some(keep);
         Ok(())
     }
+
+    /// Point a destination published with
+    /// [`set_generations`](#method.set_generations) back at the
+    /// generation before the one it currently links to, returning the
+    /// path of that generation.
+    ///
+    /// If `sync_dir` is set, the directory is synced to stable storage
+    /// after the link is replaced, as with
+    /// [`set_sync_dir`](#method.set_sync_dir).
+    #[cfg(unix)]
+    pub fn rollback<P: AsRef<path::Path>>(filename: P, sync_dir: bool) -> Result<path::PathBuf> {
+        generations::rollback(&OsFilesystem, filename.as_ref(), sync_dir)
+    }
+
+    /// Set the filesystem layer used to sync and rename the file.
+    #[cfg(test)]
+    pub(crate) fn set_filesystem<F: Filesystem + 'static>(&mut self, filesystem: F) {
+        self.filesystem = Box::new(filesystem);
+    }
+
+    /// Finish the CDB file and rename it into place, returning
+    /// statistics about the build.
+    pub fn finish(self) -> Result<BuildStats> {
+        self.publish().map(|(_, stats)| stats)
+    }
+
+    /// Set an observer to be notified of progress while building the
+    /// file.
+    ///
+    /// See [`BuildObserver`](trait.BuildObserver.html).
+    pub fn set_observer<O: BuildObserver + 'static>(&mut self, observer: O) {
+        self.cdb.as_mut().unwrap().set_observer(observer)
+    }
+
+    /// Write a minimal perfect hash index in place of the standard hash
+    /// tables, if every key is unique. **Other CDB readers see such a
+    /// file as empty.**
+    ///
+    /// See [`CDBMake::set_perfect_hash`](struct.CDBMake.html#method.set_perfect_hash).
+    pub fn set_perfect_hash(&mut self, perfect: bool) -> Result<()> {
+        self.cdb.as_mut().unwrap().set_perfect_hash(perfect)
+    }
+
+    /// Keep a sorted index of the keys in the file.
+    ///
+    /// See [`CDBMake::set_key_index`](struct.CDBMake.html#method.set_key_index).
+    pub fn set_key_index(&mut self, index: bool) -> Result<()> {
+        self.cdb.as_mut().unwrap().set_key_index(index)
+    }
+
+    /// Normalize keys before hashing and comparing them.
+    ///
+    /// See [`CDBMake::set_normalizer`](struct.CDBMake.html#method.set_normalizer).
+    pub fn set_normalizer<N: Normalizer + 'static>(&mut self, normalizer: N) -> Result<()> {
+        self.cdb.as_mut().unwrap().set_normalizer(normalizer)
+    }
+
+    /// Add a Bloom filter of the keys to the file.
+    ///
+    /// See [`CDBMake::set_bloom_filter`](struct.CDBMake.html#method.set_bloom_filter).
+    pub fn set_bloom_filter(&mut self, bits_per_key: u32) -> Result<()> {
+        self.cdb.as_mut().unwrap().set_bloom_filter(bits_per_key)
+    }
+
+    /// Set the number of hash table slots allocated for each record.
+    ///
+    /// See [`CDBMake::set_slots_per_entry`](struct.CDBMake.html#method.set_slots_per_entry).
+    pub fn set_slots_per_entry(&mut self, ratio: f64) -> Result<()> {
+        self.cdb.as_mut().unwrap().set_slots_per_entry(ratio)
+    }
+
+    /// Abandon the CDB file, removing the temporary file and leaving
+    /// any existing destination untouched.
+    ///
+    /// Unlike dropping the writer, this reports any error from removing
+    /// the temporary file.
+    pub fn abort(mut self) -> Result<()> {
+        self.cleanup = false;
+        // Close the file before removing it.
+        self.cdb.take();
+        self.remove_tmp()
+    }
+
+    fn remove_tmp(&self) -> Result<()> {
+        match &self.dir {
+            #[cfg(unix)]
+            Some(dir) => remove_at(dir, &self.tmpname),
+            _ => self.filesystem.remove_file(&self.tmpname),
+        }
+    }
+
+    /// Finish the CDB file, then sync and rename it into place. If any
+    /// step before the rename fails, the temporary file is removed when
+    /// the writer is dropped.
+    fn publish(mut self) -> Result<(fs::File, BuildStats)> {
+        let (file, mut stats) = self.cdb.take().unwrap().finish_file()?;
+        let start = Instant::now();
+        let dstname = self.dstname.as_path();
+        if self.preserve_attributes {
+            match self.filesystem.copy_attributes(dstname, &file) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+                result => result?,
+            }
+        }
+        if self.sync_file {
+            self.filesystem.sync_file(&file)?;
+        }
+        let tmpname = self.tmpname.as_path();
+        match (&self.dir, self.generations) {
+            #[cfg(unix)]
+            (Some(dir), _) => self.filesystem.rename_at(dir, tmpname, dstname)?,
+            #[cfg(unix)]
+            (None, Some(keep)) => generations::publish(&*self.filesystem, tmpname, dstname, keep)?,
+            _ => self.filesystem.rename(tmpname, dstname)?,
+        }
+        self.cleanup = false;
+        if self.sync_dir {
+            match &self.dir {
+                #[cfg(unix)]
+                Some(dir) => self.filesystem.sync_dir_handle(dir)?,
+                _ => {
+                    let dir = match dstname.parent() {
+                        Some(dir) if dir != path::Path::new("") => dir,
+                        _ => path::Path::new("."),
+                    };
+                    self.filesystem.sync_dir(dir)?;
+                }
+            }
+        }
+        stats.publishing = start.elapsed();
+        Ok((file, stats))
+    }
+
+    /// Finish the CDB file and open it for reading.
+    ///
+    /// The returned reader is mapped from the same file that was just
+    /// written and renamed, not by opening the destination name again,
+    /// so it cannot see a file put in place by another writer.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// fn main() -> std::io::Result<()> {
+    ///     let mut cdb = cdb::CDBWriter::create("temporary.cdb")?;
+    ///     cdb.add(b"one", b"Hello")?;
+    ///     let (cdb, stats) = cdb.finish_and_open()?;
+    ///     println!("{} records in {} bytes", stats.records, stats.file_size);
+    ///     assert_eq!(cdb.get(b"one").unwrap()?, b"Hello");
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn finish_and_open(self) -> Result<(CDB, BuildStats)> {
+        // Open with the writer's own normalizer, which need not be one
+        // that CDB knows by name.
+        let normalizer = self.cdb.as_ref().unwrap().normalizer.clone();
+        let (file, stats) = self.publish()?;
+        Ok((CDB::from_file_with(file, normalizer)?, stats))
+    }
 }
 
 impl Drop for CDBWriter {
     #[allow(unused_must_use)]
     fn drop(&mut self) {
-        if let Some(_) = self.cdb {
-            fs::remove_file(&self.tmpname);
+        if self.cleanup {
+            self.cdb.take();
+            self.remove_tmp();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Filesystem for Recorder {
+        fn sync_file(&self, file: &fs::File) -> Result<()> {
+            self.0.lock().unwrap().push("sync_file".into());
+            OsFilesystem.sync_file(file)
         }
+        fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("rename {}", to.display()));
+            OsFilesystem.rename(from, to)
+        }
+        fn sync_dir(&self, dir: &Path) -> Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("sync_dir {}", dir.display()));
+            OsFilesystem.sync_dir(dir)
+        }
+    }
+
+    #[test]
+    fn test_sync_order() {
+        let filename = "tests/writer_sync.cdb";
+        let recorder = Recorder::default();
+        let mut cdb = CDBWriter::create(filename).unwrap();
+        cdb.set_filesystem(recorder.clone());
+        cdb.add(b"one", b"Hello").unwrap();
+        cdb.finish().unwrap();
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                "sync_file",
+                "rename tests/writer_sync.cdb",
+                "sync_dir tests"
+            ]
+        );
+
+        let recorder = Recorder::default();
+        let mut cdb = CDBWriter::create(filename).unwrap();
+        cdb.set_filesystem(recorder.clone());
+        cdb.set_sync_file(false);
+        cdb.set_sync_dir(false);
+        cdb.add(b"one", b"Hello").unwrap();
+        cdb.finish().unwrap();
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec!["rename tests/writer_sync.cdb"]
+        );
+
+        fs::remove_file(filename).unwrap();
+    }
+
+    /// Simulates another publisher claiming a generation name first,
+    /// and a filesystem which refuses to remove old generations.
+    #[cfg(unix)]
+    #[derive(Default)]
+    struct Contended(Mutex<bool>);
+
+    #[cfg(unix)]
+    impl Filesystem for Contended {
+        fn hard_link(&self, from: &Path, to: &Path) -> Result<()> {
+            let mut raced = self.0.lock().unwrap();
+            if !*raced {
+                *raced = true;
+                fs::write(to, b"")?;
+            }
+            OsFilesystem.hard_link(from, to)
+        }
+        fn remove_file(&self, path: &Path) -> Result<()> {
+            if path.extension() == Some("tmp".as_ref()) {
+                OsFilesystem.remove_file(path)
+            } else {
+                Err(io::Error::from(io::ErrorKind::PermissionDenied))
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_generations_contended() {
+        let dir = "tests/writer_generations";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir(dir).unwrap();
+        let filename = "tests/writer_generations/data.cdb";
+
+        for _ in 0..2 {
+            let mut cdb = CDBWriter::create(filename).unwrap();
+            cdb.set_filesystem(Contended::default());
+            cdb.set_generations(1).unwrap();
+            cdb.add(b"one", b"Hello").unwrap();
+            cdb.finish().unwrap();
+        }
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "data.cdb",
+                "data.cdb.1",
+                "data.cdb.2",
+                "data.cdb.3",
+                "data.cdb.4"
+            ]
+        );
+        assert_eq!(fs::read_link(filename).unwrap(), Path::new("data.cdb.4"));
+        assert_eq!(
+            fs::metadata("tests/writer_generations/data.cdb.3")
+                .unwrap()
+                .len(),
+            0
+        );
+
+        fs::remove_dir_all(dir).unwrap();
     }
 }
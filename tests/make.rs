@@ -50,3 +50,939 @@ fn test_make() {
 
     noerr!(fs::remove_file(filename));
 }
+
+#[test]
+fn test_make_reader() {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let filename = "tests/make_reader.cdb";
+    let big: Vec<u8> = (0..100000u32).map(|i| i as u8).collect();
+
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.add_reader(b"big", big.len() as u64, &big[..]));
+    noerr!(cdb.add(b"small", b"value"));
+    assert!(cdb.add_reader(b"short", 10, &b"12345"[..]).is_err());
+    drop(cdb);
+
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.add_reader(b"big", big.len() as u64, &big[..]));
+    noerr!(cdb.add(b"small", b"value"));
+    noerr!(cdb.finish());
+
+    let cdb = cdb::CDB::open(filename).unwrap();
+    let mut reader = cdb.get_reader(b"big").unwrap().unwrap();
+    assert_eq!(reader.len(), big.len() as u64);
+    let mut value = Vec::new();
+    noerr!(reader.read_to_end(&mut value));
+    assert_eq!(value, big);
+    assert_eq!(reader.seek(SeekFrom::End(-10)).unwrap(), 99990);
+    let mut tail = Vec::new();
+    noerr!(reader.read_to_end(&mut tail));
+    assert_eq!(tail, &big[99990..]);
+
+    let mut readers = cdb.find_readers(b"small");
+    let mut value = String::new();
+    noerr!(readers.next().unwrap().unwrap().read_to_string(&mut value));
+    assert_eq!(value, "value");
+    assert!(readers.next().is_none());
+    assert!(cdb.get_reader(b"missing").is_none());
+
+    noerr!(fs::remove_file(filename));
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_parallel_make() {
+    let records: Vec<(Vec<u8>, Vec<u8>)> = (0..20000u32)
+        .map(|i| {
+            let key = format!("{}", i % 7000).into_bytes();
+            (key, vec![i as u8; (i % 100) as usize])
+        })
+        .collect();
+
+    // A single stage produces exactly what CDBMake does.
+    let serial = "tests/make_parallel_serial.cdb";
+    let mut cdb = cdb::CDBMake::new(fs::File::create(serial).unwrap()).unwrap();
+    for (key, value) in &records {
+        noerr!(cdb.add(key, value));
+    }
+    noerr!(cdb.finish());
+    let single = "tests/make_parallel_single.cdb";
+    let cdb = cdb::CDBParallelMake::new(fs::File::create(single).unwrap()).unwrap();
+    let mut stage = cdb.stage();
+    for (key, value) in &records {
+        noerr!(stage.add(key, value));
+    }
+    noerr!(stage.finish());
+    noerr!(cdb.finish());
+    assert_eq!(fs::read(serial).unwrap(), fs::read(single).unwrap());
+
+    let filename = "tests/make_parallel.cdb";
+    let cdb = cdb::CDBParallelMake::new(fs::File::create(filename).unwrap()).unwrap();
+    std::thread::scope(|scope| {
+        for chunk in records.chunks(3000) {
+            let cdb = &cdb;
+            scope.spawn(move || {
+                let mut stage = cdb.stage();
+                for (key, value) in chunk {
+                    noerr!(stage.add(key, value));
+                }
+            });
+        }
+    });
+    noerr!(cdb.finish());
+
+    let cdb = cdb::CDB::open(filename).unwrap();
+    let mut all: Vec<_> = cdb.iter().map(|r| r.unwrap()).collect();
+    all.sort();
+    let mut expected = records.clone();
+    expected.sort();
+    assert_eq!(all, expected);
+    for (key, _) in records.iter().take(100) {
+        let values: Vec<_> = cdb.find(key).map(|r| r.unwrap()).collect();
+        let expected: Vec<_> = cdb
+            .iter()
+            .map(|r| r.unwrap())
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v)
+            .collect();
+        assert_eq!(values, expected);
+    }
+
+    noerr!(fs::remove_file(serial));
+    noerr!(fs::remove_file(single));
+    noerr!(fs::remove_file(filename));
+}
+
+#[test]
+fn test_make_sorted() {
+    let records: Vec<(Vec<u8>, Vec<u8>)> = (0..5000u32)
+        .map(|i| {
+            (
+                format!("{}", i * 7919 % 1000).into_bytes(),
+                format!("{}", i).into_bytes(),
+            )
+        })
+        .collect();
+
+    let build = |filename: &str, records: &[(Vec<u8>, Vec<u8>)], memory: usize| {
+        let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+        noerr!(cdb.sort());
+        cdb.set_sort_memory(memory);
+        for (key, value) in records {
+            noerr!(cdb.add(key, value));
+        }
+        noerr!(cdb.finish());
+        fs::read(filename).unwrap()
+    };
+    let mut reversed = records.clone();
+    reversed.reverse();
+    let forward = build("tests/make_sorted1.cdb", &records, 1 << 20);
+    let backward = build("tests/make_sorted2.cdb", &reversed, 1 << 20);
+    let external = build("tests/make_sorted3.cdb", &reversed, 10000);
+    assert_eq!(forward, backward);
+    assert_eq!(forward, external);
+
+    let cdb = cdb::CDB::open("tests/make_sorted3.cdb").unwrap();
+    let all: Vec<_> = cdb.iter().map(|r| r.unwrap()).collect();
+    let mut expected = records.clone();
+    expected.sort();
+    assert_eq!(all, expected);
+
+    let filename = "tests/make_sorted4.cdb";
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.sort_by(|k1, _, k2, _| k2.cmp(k1)));
+    cdb.set_sort_memory(10000);
+    for (key, value) in &records {
+        noerr!(cdb.add(key, value));
+    }
+    noerr!(cdb.finish());
+    let cdb = cdb::CDB::open(filename).unwrap();
+    let keys: Vec<_> = cdb.keys().map(|r| r.unwrap()).collect();
+    let mut expected: Vec<_> = records.iter().map(|r| r.0.clone()).collect();
+    expected.sort_by(|a, b| b.cmp(a));
+    assert_eq!(keys, expected);
+    // Equal keys keep their insertion order.
+    let values: Vec<_> = cdb.find(b"0").map(|r| r.unwrap()).collect();
+    let expected: Vec<_> = records
+        .iter()
+        .filter(|r| r.0 == b"0")
+        .map(|r| r.1.clone())
+        .collect();
+    assert_eq!(values, expected);
+
+    let mut cdb = cdb::CDBWriter::create("tests/make_sorted5.cdb").unwrap();
+    noerr!(cdb.add(b"one", b"two"));
+    assert!(cdb.sort().is_err());
+    drop(cdb);
+
+    for i in 1..=4 {
+        noerr!(fs::remove_file(format!("tests/make_sorted{}.cdb", i)));
+    }
+}
+
+#[test]
+fn test_make_unique() {
+    use cdb::Duplicates;
+
+    let build = |filename: &str, policy: Duplicates| {
+        let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+        noerr!(cdb.unique_keys(policy));
+        noerr!(cdb.add(b"one", b"first"));
+        noerr!(cdb.add(b"two", b"Goodbye"));
+        let result = cdb.add(b"one", b"second");
+        noerr!(cdb.add_reader(b"one", 5, &b"third"[..]));
+        noerr!(cdb.finish());
+        result
+    };
+
+    let filename = "tests/make_unique_first.cdb";
+    noerr!(build(filename, Duplicates::KeepFirst));
+    let cdb = cdb::CDB::open(filename).unwrap();
+    let values: Vec<_> = cdb.find(b"one").map(|r| r.unwrap()).collect();
+    assert_eq!(values, vec![b"first".to_vec()]);
+    assert_eq!(cdb.iter().count(), 2);
+    noerr!(fs::remove_file(filename));
+
+    let filename = "tests/make_unique_last.cdb";
+    noerr!(build(filename, Duplicates::KeepLast));
+    let cdb = cdb::CDB::open(filename).unwrap();
+    let values: Vec<_> = cdb.find(b"one").map(|r| r.unwrap()).collect();
+    assert_eq!(values, vec![b"third".to_vec()]);
+    assert_eq!(cdb.get(b"two").unwrap().unwrap(), b"Goodbye");
+    // Superseded records are removed from the file entirely.
+    let all: Vec<_> = cdb.iter().map(|r| r.unwrap()).collect();
+    assert_eq!(
+        all,
+        vec![
+            (b"two".to_vec(), b"Goodbye".to_vec()),
+            (b"one".to_vec(), b"third".to_vec())
+        ]
+    );
+    assert_eq!(cdb.iter_from(cdb.iter().cursor()).unwrap().count(), 2);
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::prelude::*;
+        assert_eq!(cdb.par_iter().count(), 2);
+    }
+    let len = cdb.file().metadata().unwrap().len();
+    assert_eq!(len, 2048 + 8 + 3 + 7 + 8 + 3 + 5 + 4 * 8);
+    noerr!(fs::remove_file(filename));
+
+    // Compacting moves many records, some across copy buffers
+    let filename = "tests/make_unique_last_many.cdb";
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.unique_keys(Duplicates::KeepLast));
+    for i in 0..3000 {
+        let value = vec![b'a' + (i % 26) as u8; i % 100];
+        noerr!(cdb.add(format!("{}", i % 1000).as_bytes(), &value));
+    }
+    noerr!(cdb.finish());
+    let cdb = cdb::CDB::open(filename).unwrap();
+    assert_eq!(cdb.iter().count(), 1000);
+    for i in 2000..3000 {
+        let value = vec![b'a' + (i % 26) as u8; i % 100];
+        let key = format!("{}", i % 1000);
+        let values: Vec<_> = cdb.find(key.as_bytes()).map(|r| r.unwrap()).collect();
+        assert_eq!(values, vec![value]);
+    }
+    noerr!(fs::remove_file(filename));
+
+    let filename = "tests/make_unique_error.cdb";
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.unique_keys(Duplicates::Error));
+    noerr!(cdb.add(b"one", b"first"));
+    // Compare against keys both in the file and still buffered
+    noerr!(cdb.add(b"big", &[0; 100000]));
+    noerr!(cdb.add(b"three", b"third"));
+    assert!(cdb.add(b"big", b"").is_err());
+    assert!(cdb.add(b"three", b"").is_err());
+    let err = cdb.add(b"one", b"second").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    let dup = err.get_ref().unwrap().downcast_ref::<cdb::DuplicateKey>();
+    assert_eq!(dup.unwrap().key, b"one");
+    noerr!(cdb.add(b"two", b"second"));
+    noerr!(cdb.finish());
+    let cdb = cdb::CDB::open(filename).unwrap();
+    assert_eq!(cdb.iter().count(), 4);
+    noerr!(fs::remove_file(filename));
+
+    // Works together with sorting, checking keys in sorted order
+    let filename = "tests/make_unique_sorted.cdb";
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.sort());
+    noerr!(cdb.unique_keys(Duplicates::KeepFirst));
+    noerr!(cdb.add(b"one", b"z"));
+    noerr!(cdb.add(b"one", b"a"));
+    noerr!(cdb.finish());
+    let cdb = cdb::CDB::open(filename).unwrap();
+    assert_eq!(cdb.iter().count(), 1);
+    assert_eq!(cdb.get(b"one").unwrap().unwrap(), b"a");
+    noerr!(fs::remove_file(filename));
+
+    // Sorted duplicates are only found when the file is finished
+    let filename = "tests/make_unique_sorted_error.cdb";
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.sort());
+    noerr!(cdb.unique_keys(Duplicates::Error));
+    noerr!(cdb.add(b"one", b"z"));
+    noerr!(cdb.add(b"two", b"z"));
+    noerr!(cdb.add(b"one", b"a"));
+    let err = cdb.finish().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    let dup = err.get_ref().unwrap().downcast_ref::<cdb::DuplicateKey>();
+    assert_eq!(dup.unwrap().key, b"one");
+    assert!(fs::metadata(filename).is_err());
+
+    // Keys are read back, so the file must be readable
+    let filename = "tests/make_unique_writeonly.cdb";
+    let mut cdb = cdb::CDBMake::new(fs::File::create(filename).unwrap()).unwrap();
+    let err = cdb.unique_keys(Duplicates::Error).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    noerr!(cdb.add(b"one", b"first"));
+    noerr!(cdb.add(b"one", b"second"));
+    noerr!(cdb.finish());
+    noerr!(fs::remove_file(filename));
+}
+
+#[test]
+fn test_make_finish_and_open() {
+    let filename = "tests/make_open.cdb";
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.add(b"one", b"Hello"));
+    noerr!(cdb.add(b"two", b"Goodbye"));
+    noerr!(cdb.add(b"one", b", World!"));
+    let (cdb, stats) = cdb.finish_and_open().unwrap();
+    // Replacing the destination does not affect the open reader.
+    noerr!(fs::remove_file(filename));
+
+    assert_eq!(stats.records, 3);
+    assert_eq!(stats.largest_bucket, 2);
+    assert_eq!(stats.file_size, cdb.file().metadata().unwrap().len() as u32);
+    let values: Vec<_> = cdb.find(b"one").map(|r| r.unwrap()).collect();
+    assert_eq!(values, vec![b"Hello".to_vec(), b", World!".to_vec()]);
+    assert_eq!(cdb.get(b"two").unwrap().unwrap(), b"Goodbye");
+}
+
+#[test]
+fn test_make_unique_and_locked() {
+    let filename = "tests/make_exclusive.cdb";
+
+    let mut one = cdb::CDBWriter::create_unique(filename).unwrap();
+    let mut two = cdb::CDBWriter::create_unique(filename).unwrap();
+    noerr!(one.add(b"one", b"first"));
+    noerr!(two.add(b"one", b"second"));
+    noerr!(one.finish());
+    noerr!(two.finish());
+    let cdb = cdb::CDB::open(filename).unwrap();
+    assert_eq!(cdb.get(b"one").unwrap().unwrap(), b"second");
+
+    let mut one = cdb::CDBWriter::create_locked(filename).unwrap();
+    let err = cdb::CDBWriter::create_locked(filename).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+    noerr!(one.add(b"one", b"third"));
+    noerr!(one.finish());
+    let two = cdb::CDBWriter::create_locked(filename).unwrap();
+    drop(two);
+
+    let leftovers = fs::read_dir("tests")
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with("make_exclusive.cdb.tmp"))
+        .count();
+    assert_eq!(leftovers, 0);
+    noerr!(fs::remove_file(filename));
+    noerr!(fs::remove_file("tests/make_exclusive.cdb.lock"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_make_preserve_attributes() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let filename = "tests/make_preserve.cdb";
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.add(b"one", b"Hello"));
+    noerr!(cdb.finish());
+    noerr!(fs::set_permissions(
+        filename,
+        fs::Permissions::from_mode(0o640)
+    ));
+    #[cfg(target_os = "linux")]
+    let xattr = {
+        let path = std::ffi::CString::new(filename).unwrap();
+        let name = std::ffi::CString::new("user.cdb-test").unwrap();
+        let result = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                b"value".as_ptr() as *const _,
+                5,
+                0,
+            )
+        };
+        // Not all filesystems support user extended attributes.
+        result == 0
+    };
+
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.set_preserve_attributes(true));
+    noerr!(cdb.add(b"one", b"Goodbye"));
+    noerr!(cdb.finish());
+
+    let meta = fs::metadata(filename).unwrap();
+    assert_eq!(meta.permissions().mode() & 0o7777, 0o640);
+    #[cfg(target_os = "linux")]
+    if xattr {
+        let path = std::ffi::CString::new(filename).unwrap();
+        let name = std::ffi::CString::new("user.cdb-test").unwrap();
+        let mut buf = [0u8; 16];
+        let len = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut _,
+                buf.len(),
+            )
+        };
+        assert_eq!(&buf[..len as usize], b"value");
+    }
+    let cdb = cdb::CDB::open(filename).unwrap();
+    assert_eq!(cdb.get(b"one").unwrap().unwrap(), b"Goodbye");
+
+    noerr!(fs::remove_file(filename));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_make_generations() {
+    let dir = "tests/make_generations";
+    let _ = fs::remove_dir_all(dir);
+    noerr!(fs::create_dir(dir));
+    let filename = "tests/make_generations/data.cdb";
+
+    // A file published without generations is kept as generation 0.
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.add(b"generation", b"0"));
+    noerr!(cdb.finish());
+
+    for i in 1..=4u8 {
+        let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+        noerr!(cdb.set_generations(2));
+        noerr!(cdb.add(b"generation", &[b'0' + i]));
+        noerr!(cdb.finish());
+        let cdb = cdb::CDB::open(filename).unwrap();
+        assert_eq!(cdb.get(b"generation").unwrap().unwrap(), &[b'0' + i]);
+        if i == 1 {
+            let cdb = cdb::CDB::open("tests/make_generations/data.cdb.0").unwrap();
+            assert_eq!(cdb.get(b"generation").unwrap().unwrap(), b"0");
+        }
+    }
+    let mut names: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["data.cdb", "data.cdb.3", "data.cdb.4"]);
+    assert_eq!(
+        fs::read_link(filename).unwrap(),
+        std::path::Path::new("data.cdb.4")
+    );
+
+    let previous = cdb::CDBWriter::rollback(filename, true).unwrap();
+    assert_eq!(
+        previous,
+        std::path::Path::new("tests/make_generations/data.cdb.3")
+    );
+    let cdb = cdb::CDB::open(filename).unwrap();
+    assert_eq!(cdb.get(b"generation").unwrap().unwrap(), b"3");
+    assert!(cdb::CDBWriter::rollback(filename, false).is_err());
+
+    // Publishing after a rollback still produces a new generation.
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.set_generations(2));
+    noerr!(cdb.add(b"generation", b"5"));
+    noerr!(cdb.finish());
+    assert_eq!(
+        fs::read_link(filename).unwrap(),
+        std::path::Path::new("data.cdb.5")
+    );
+
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    let err = cdb.set_generations(0).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    noerr!(cdb.abort());
+
+    noerr!(fs::remove_dir_all(dir));
+}
+
+/// A reader which fails after producing some data.
+struct FailingReader(usize);
+
+impl std::io::Read for FailingReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.0 == 0 {
+            return Err(std::io::Error::other("Read failed"));
+        }
+        let n = self.0.min(buf.len());
+        buf[..n].iter_mut().for_each(|b| *b = b'x');
+        self.0 -= n;
+        Ok(n)
+    }
+}
+
+#[test]
+fn test_make_abort() {
+    let filename = "tests/make_abort.cdb";
+    let tmpname = "tests/make_abort.cdb.tmp";
+    let _ = fs::remove_file(filename);
+
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.add(b"one", b"1"));
+    assert!(fs::metadata(tmpname).is_ok());
+    noerr!(cdb.abort());
+    assert!(fs::metadata(tmpname).is_err());
+    assert!(fs::metadata(filename).is_err());
+
+    // A failed read leaves a partial record, so the writer is poisoned.
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.add(b"one", b"1"));
+    assert!(cdb.add_reader(b"two", 10, FailingReader(4)).is_err());
+    let err = cdb.add(b"three", b"3").unwrap_err();
+    assert_eq!(err.to_string(), "Read failed");
+    assert!(cdb.add_reader(b"four", 1, &b"4"[..]).is_err());
+    assert!(cdb.finish().is_err());
+    assert!(fs::metadata(tmpname).is_err());
+    assert!(fs::metadata(filename).is_err());
+
+    // Errors which write nothing leave the writer usable.
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.unique_keys(cdb::Duplicates::Error));
+    noerr!(cdb.add(b"one", b"1"));
+    assert!(cdb.add(b"one", b"2").is_err());
+    noerr!(cdb.add(b"two", b"2"));
+    noerr!(cdb.finish());
+    let cdb = cdb::CDB::open(filename).unwrap();
+    assert_eq!(cdb.get(b"one").unwrap().unwrap(), b"1");
+    assert_eq!(cdb.get(b"two").unwrap().unwrap(), b"2");
+
+    noerr!(fs::remove_file(filename));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_make_os_paths() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+
+    let dir = PathBuf::from("tests").join(OsStr::from_bytes(b"make_paths_\xff"));
+    let _ = fs::remove_dir_all(&dir);
+    noerr!(fs::create_dir(&dir));
+
+    let filename = dir.join(OsStr::from_bytes(b"data\xfe.cdb"));
+    let mut cdb = cdb::CDBWriter::create(&filename).unwrap();
+    noerr!(cdb.add(b"one", b"1"));
+    noerr!(cdb.finish());
+    let cdb = cdb::CDB::open(&filename).unwrap();
+    assert_eq!(cdb.get(b"one").unwrap().unwrap(), b"1");
+
+    let mut cdb = cdb::CDBWriter::with_suffix(&filename, OsStr::from_bytes(b".\xfd")).unwrap();
+    noerr!(cdb.add(b"two", b"2"));
+    noerr!(cdb.finish());
+    let cdb = cdb::CDB::open(&filename).unwrap();
+    assert_eq!(cdb.get(b"two").unwrap().unwrap(), b"2");
+
+    // Creating relative to a directory handle is unaffected by the
+    // directory being renamed.
+    let handle = fs::File::open(&dir).unwrap();
+    let mut cdb = cdb::CDBWriter::create_at(&handle, OsStr::from_bytes(b"at\xfe.cdb")).unwrap();
+    let moved = PathBuf::from("tests/make_paths_moved");
+    let _ = fs::remove_dir_all(&moved);
+    noerr!(fs::rename(&dir, &moved));
+    noerr!(cdb.add(b"three", b"3"));
+    noerr!(cdb.finish());
+    let cdb = cdb::CDB::open(moved.join(OsStr::from_bytes(b"at\xfe.cdb"))).unwrap();
+    assert_eq!(cdb.get(b"three").unwrap().unwrap(), b"3");
+
+    let mut cdb = cdb::CDBWriter::create_at(&handle, "aborted.cdb").unwrap();
+    noerr!(cdb.add(b"four", b"4"));
+    noerr!(cdb.abort());
+    let mut cdb = cdb::CDBWriter::create_at(&handle, "generations.cdb").unwrap();
+    let err = cdb.set_generations(2).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    let err = cdb.set_preserve_attributes(true).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    noerr!(cdb.set_preserve_attributes(false));
+    noerr!(cdb.abort());
+    assert_eq!(fs::read_dir(&moved).unwrap().count(), 2);
+
+    noerr!(fs::remove_dir_all(&moved));
+}
+
+#[test]
+fn test_make_observer() {
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Counts {
+        added: u64,
+        bytes: u64,
+        sorted: u64,
+        tables: Vec<usize>,
+        table_bytes: u64,
+        finished: u64,
+    }
+
+    #[derive(Clone, Default)]
+    struct Progress(Arc<Mutex<Counts>>);
+
+    impl cdb::BuildObserver for Progress {
+        fn added(&mut self, records: u64, bytes: u64) {
+            let mut counts = self.0.lock().unwrap();
+            assert_eq!(records, counts.added + 1);
+            counts.added = records;
+            counts.bytes = bytes;
+        }
+        fn sorted(&mut self, records: u64, _bytes: u64) {
+            self.0.lock().unwrap().sorted = records;
+        }
+        fn table_written(&mut self, table: usize, bytes: u64) {
+            let mut counts = self.0.lock().unwrap();
+            counts.tables.push(table);
+            counts.table_bytes = bytes;
+        }
+        fn finished(&mut self, bytes: u64) {
+            self.0.lock().unwrap().finished = bytes;
+        }
+    }
+
+    let filename = "tests/make_observer.cdb";
+    let progress = Progress::default();
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.sort());
+    cdb.set_observer(progress.clone());
+    noerr!(cdb.add(b"one", b"Hello"));
+    noerr!(cdb.add(b"two", b"Goodbye"));
+    noerr!(cdb.add_reader(b"one", 8, &b", World!"[..]));
+    let stats = cdb.finish().unwrap();
+
+    let size = fs::metadata(filename).unwrap().len();
+    {
+        let counts = progress.0.lock().unwrap();
+        assert_eq!(counts.added, 3);
+        assert_eq!(counts.bytes, 3 * 8 + 8 + 10 + 11);
+        assert_eq!(counts.sorted, 3);
+        assert_eq!(counts.tables, (0..256).collect::<Vec<_>>());
+        assert_eq!(counts.table_bytes, size);
+        assert_eq!(counts.finished, size);
+    }
+
+    assert_eq!(stats.records, 3);
+    assert_eq!(stats.largest_bucket, 2);
+    assert_eq!(stats.file_size as u64, size);
+    assert_eq!(
+        stats.total(),
+        stats.adding
+            + stats.sorting
+            + stats.compacting
+            + stats.indexing
+            + stats.tables
+            + stats.publishing
+    );
+
+    // Skipped duplicates are not counted, and the trailer is included
+    // in the bytes written.
+    let progress = Progress::default();
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.unique_keys(cdb::Duplicates::KeepFirst));
+    noerr!(cdb.set_bloom_filter(10));
+    cdb.set_observer(progress.clone());
+    noerr!(cdb.add(b"one", b"Hello"));
+    noerr!(cdb.add(b"one", b"Goodbye"));
+    noerr!(cdb.add_reader(b"one", 8, &b", World!"[..]));
+    let (_, stats) = cdb.finish_and_open().unwrap();
+    let counts = progress.0.lock().unwrap();
+    assert_eq!(counts.added, 1);
+    assert_eq!(counts.bytes, 8 + 3 + 5);
+    assert!(counts.table_bytes < stats.file_size as u64);
+    assert_eq!(counts.finished, stats.file_size as u64);
+    assert_eq!(stats.records, 1);
+    noerr!(fs::remove_file(filename));
+}
+
+#[test]
+fn test_make_slots_per_entry() {
+    let filename = "tests/make_slots.cdb";
+    let mut sizes = Vec::new();
+    for &ratio in &[1.01, 1.5, 2.0, 4.0] {
+        let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+        noerr!(cdb.set_slots_per_entry(ratio));
+        for i in 0..1000 {
+            noerr!(cdb.add(format!("key{}", i).as_bytes(), b"value"));
+        }
+        let (cdb, stats) = cdb.finish_and_open().unwrap();
+        for i in 0..1000 {
+            assert_eq!(
+                cdb.get(format!("key{}", i).as_bytes()).unwrap().unwrap(),
+                b"value"
+            );
+            assert!(cdb.get(format!("missing{}", i).as_bytes()).is_none());
+        }
+        sizes.push(stats.file_size);
+    }
+    assert!(sizes.windows(2).all(|w| w[0] < w[1]));
+
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    assert!(cdb.set_slots_per_entry(1.0).is_err());
+    assert!(cdb.set_slots_per_entry(f64::NAN).is_err());
+    noerr!(cdb.abort());
+    noerr!(fs::remove_file(filename));
+}
+
+#[test]
+fn test_make_bloom_filter() {
+    let filename = "tests/make_bloom.cdb";
+    let mut sizes = Vec::new();
+    for &bloom in &[false, true] {
+        let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+        if bloom {
+            noerr!(cdb.set_bloom_filter(10));
+        }
+        for i in 0..1000 {
+            noerr!(cdb.add(format!("key{}", i).as_bytes(), b"value"));
+        }
+        noerr!(cdb.add(b"key0", b"again"));
+        let (cdb, stats) = cdb.finish_and_open().unwrap();
+        sizes.push(stats.file_size);
+        for i in 0..1000 {
+            assert_eq!(
+                cdb.get(format!("key{}", i).as_bytes()).unwrap().unwrap(),
+                b"value"
+            );
+            assert!(cdb.get(format!("missing{}", i).as_bytes()).is_none());
+        }
+        let values: Vec<_> = cdb.find(b"key0").map(|r| r.unwrap()).collect();
+        assert_eq!(values, vec![b"value".to_vec(), b"again".to_vec()]);
+        // The filter is not seen as records.
+        assert_eq!(cdb.iter().count(), 1001);
+        assert_eq!(cdb.distinct_keys().count(), 1000);
+    }
+    // Roughly 10 bits per key, plus the trailer.
+    assert!(sizes[1] > sizes[0] + 1001 * 10 / 8);
+    assert!(sizes[1] < sizes[0] + 1001 * 11 / 8 + 64);
+
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    assert!(cdb.set_bloom_filter(0).is_err());
+    assert!(cdb.set_bloom_filter(65).is_err());
+    assert!(cdb.set_bloom_filter(u32::MAX).is_err());
+    noerr!(cdb.abort());
+    noerr!(fs::remove_file(filename));
+}
+
+#[test]
+fn test_make_perfect_hash() {
+    let filename = "tests/make_perfect.cdb";
+    let mut sizes = Vec::new();
+    for &perfect in &[false, true] {
+        let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+        noerr!(cdb.set_perfect_hash(perfect));
+        for i in 0..10000 {
+            noerr!(cdb.add(format!("key{}", i).as_bytes(), format!("{}", i).as_bytes()));
+        }
+        let (cdb, stats) = cdb.finish_and_open().unwrap();
+        assert_eq!(stats.perfect_hash, perfect);
+        assert_eq!(stats.records, 10000);
+        sizes.push(stats.file_size);
+        for i in 0..10000 {
+            let key = format!("key{}", i);
+            let values: Vec<_> = cdb.find(key.as_bytes()).map(|r| r.unwrap()).collect();
+            assert_eq!(values, vec![format!("{}", i).into_bytes()]);
+            let hashed = cdb::HashedKey::new(key.as_bytes());
+            assert_eq!(
+                cdb.get_hashed(&hashed).unwrap().unwrap(),
+                format!("{}", i).into_bytes()
+            );
+            assert!(cdb.get(format!("missing{}", i).as_bytes()).is_none());
+        }
+        let keys: [&[u8]; 3] = [b"key7", b"missing", b"key9999"];
+        let results = cdb.get_many(&keys);
+        assert_eq!(results[0].as_ref().unwrap().as_ref().unwrap(), b"7");
+        assert!(results[1].is_none());
+        assert_eq!(results[2].as_ref().unwrap().as_ref().unwrap(), b"9999");
+        assert_eq!(cdb.distinct_keys().count(), 10000);
+        let mut iter = cdb.iter();
+        iter.nth(500);
+        noerr!(cdb.iter_from(iter.cursor()));
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+            assert_eq!(cdb.par_iter().count(), 10000);
+        }
+    }
+    // 5 bytes per record on average instead of 16
+    assert!(sizes[1] + 10000 * 10 < sizes[0]);
+
+    // Duplicate keys fall back to standard hash tables.
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.set_perfect_hash(true));
+    noerr!(cdb.add(b"one", b"1"));
+    noerr!(cdb.add(b"two", b"2"));
+    noerr!(cdb.add(b"one", b"3"));
+    let (cdb, stats) = cdb.finish_and_open().unwrap();
+    assert!(!stats.perfect_hash);
+    assert_eq!(cdb.find(b"one").count(), 2);
+    noerr!(fs::remove_file(filename));
+
+    // Keys are read back, so the file must be readable
+    let mut cdb = cdb::CDBMake::new(fs::File::create(filename).unwrap()).unwrap();
+    let err = cdb.set_key_index(true).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    let err = cdb.set_perfect_hash(true).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    noerr!(cdb.set_perfect_hash(false));
+    noerr!(cdb.add(b"one", b"1"));
+    noerr!(cdb.finish());
+    noerr!(fs::remove_file(filename));
+}
+
+#[test]
+fn test_make_key_index() {
+    use std::ops::Bound;
+
+    let filename = "tests/make_key_index.cdb";
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    noerr!(cdb.set_key_index(true));
+    cdb.set_bloom_filter(10).unwrap();
+    for i in (0..100).rev() {
+        for field in &["name", "email"] {
+            let key = format!("user:{:03}:{}", i, field);
+            noerr!(cdb.add(key.as_bytes(), format!("{}", i).as_bytes()));
+        }
+    }
+    noerr!(cdb.add(b"user:042:email", b"second"));
+    noerr!(cdb.add(b"zzz", b"last"));
+    let (cdb, _) = cdb.finish_and_open().unwrap();
+
+    let keys = |iter: cdb::CDBRangeIter| -> Vec<String> {
+        iter.map(|r| String::from_utf8(r.unwrap().0).unwrap())
+            .collect()
+    };
+    assert_eq!(
+        keys(cdb.prefix(b"user:042:")),
+        vec!["user:042:email", "user:042:email", "user:042:name"]
+    );
+    let values: Vec<_> = cdb
+        .prefix(b"user:042:email")
+        .map(|r| r.unwrap().1)
+        .collect();
+    assert_eq!(values, vec![b"42".to_vec(), b"second".to_vec()]);
+    assert_eq!(cdb.prefix(b"user:").count(), 201);
+    assert_eq!(cdb.prefix(b"").count(), 202);
+    assert_eq!(cdb.prefix(b"user:1000").count(), 0);
+    assert_eq!(cdb.prefix(b"zzzz").count(), 0);
+
+    assert_eq!(
+        keys(cdb.range(&b"user:098"[..]..&b"user:099:name"[..])),
+        vec!["user:098:email", "user:098:name", "user:099:email"]
+    );
+    assert_eq!(
+        keys(cdb.range(&b"user:098:name"[..]..=&b"user:099:name"[..])),
+        vec!["user:098:name", "user:099:email", "user:099:name"]
+    );
+    assert_eq!(
+        keys(cdb.range((Bound::Excluded(&b"user:099:name"[..]), Bound::Unbounded))),
+        vec!["zzz"]
+    );
+    let reversed: Vec<_> = cdb
+        .range(..&b"user:001"[..])
+        .rev()
+        .map(|r| r.unwrap().0)
+        .collect();
+    assert_eq!(
+        reversed,
+        vec![b"user:000:name".to_vec(), b"user:000:email".to_vec()]
+    );
+    assert_eq!(cdb.range(..).len(), 202);
+    assert_eq!(cdb.range(&b"b"[..]..&b"a"[..]).count(), 0);
+    noerr!(fs::remove_file(filename));
+
+    let cdb = cdb::CDB::open("tests/test1.cdb").unwrap();
+    let err = cdb.prefix(b"one").next().unwrap().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+}
+
+#[test]
+fn test_make_normalizer() {
+    struct Reversed;
+    impl cdb::Normalizer for Reversed {
+        fn name(&self) -> &str {
+            "reversed"
+        }
+        fn normalize<'a>(&self, key: &'a [u8]) -> std::borrow::Cow<'a, [u8]> {
+            key.iter().rev().cloned().collect::<Vec<u8>>().into()
+        }
+    }
+
+    let filename = "tests/make_normalizer.cdb";
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    cdb.set_normalizer(cdb::UnicodeCasefold).unwrap();
+    cdb.unique_keys(cdb::Duplicates::KeepFirst).unwrap();
+    noerr!(cdb.set_perfect_hash(true));
+    noerr!(cdb.set_key_index(true));
+    noerr!(cdb.add("Straße".as_bytes(), b"street"));
+    noerr!(cdb.add(b"STRASSE", b"ignored"));
+    noerr!(cdb.add("ΣΑΣ".as_bytes(), b"sigma"));
+    noerr!(cdb.add(b"Key:One", b"1"));
+    noerr!(cdb.add(b"key:two", b"2"));
+    assert!(cdb.set_normalizer(cdb::AsciiLowercase).is_err());
+    let (cdb, stats) = cdb.finish_and_open().unwrap();
+    assert!(stats.perfect_hash);
+    assert_eq!(stats.records, 4);
+
+    assert_eq!(cdb.get(b"strasse").unwrap().unwrap(), b"street");
+    assert_eq!(cdb.get("STRAẞE".as_bytes()).unwrap().unwrap(), b"street");
+    assert_eq!(cdb.get("σας".as_bytes()).unwrap().unwrap(), b"sigma");
+    assert_eq!(cdb.get("Σας".as_bytes()).unwrap().unwrap(), b"sigma");
+    assert_eq!(cdb.get(b"KEY:ONE").unwrap().unwrap(), b"1");
+    assert!(cdb.get(b"key:three").is_none());
+    let key = cdb::HashedKey::new(b"Key:Two");
+    assert_eq!(cdb.get_hashed(&key).unwrap().unwrap(), b"2");
+    let location = cdb.find_locations(b"key:one").next().unwrap().unwrap();
+    assert_eq!(location.key_len, 7);
+    // Keys are stored as they were added.
+    let keys: Vec<_> = cdb.prefix(b"KEY:").map(|r| r.unwrap().0).collect();
+    assert_eq!(keys, vec![b"Key:One".to_vec(), b"key:two".to_vec()]);
+    drop(cdb);
+
+    let cdb = cdb::CDB::open_with_normalizer(filename, cdb::UnicodeCasefold).unwrap();
+    assert_eq!(cdb.get(b"Key:Two").unwrap().unwrap(), b"2");
+    let err = cdb::CDB::open_with_normalizer(filename, cdb::AsciiLowercase)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    noerr!(fs::remove_file(filename));
+
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    cdb.set_normalizer(Reversed).unwrap();
+    noerr!(cdb.add(b"abc", b"value"));
+    noerr!(cdb.finish());
+    let err = cdb::CDB::open(filename).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    let cdb = cdb::CDB::open_with_normalizer(filename, Reversed).unwrap();
+    assert_eq!(cdb.get(b"abc").unwrap().unwrap(), b"value");
+    noerr!(fs::remove_file(filename));
+
+    // The writer's own normalizer is used to open the finished file.
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    cdb.set_normalizer(Reversed).unwrap();
+    noerr!(cdb.add(b"abc", b"value"));
+    let (cdb, _) = cdb.finish_and_open().unwrap();
+    assert_eq!(cdb.get(b"abc").unwrap().unwrap(), b"value");
+    noerr!(fs::remove_file(filename));
+
+    let err = cdb::CDB::open_with_normalizer("tests/test1.cdb", cdb::AsciiLowercase)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
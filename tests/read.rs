@@ -20,3 +20,280 @@ fn test_two() {
         b"Got it."
     );
 }
+
+#[test]
+fn test_get_many() {
+    let cdb = cdb::CDB::open("tests/test2.cdb").unwrap();
+    let keys: Vec<Vec<u8>> = (1..=1000u64)
+        .map(|i| (i * 3141592654 % 1000000).to_string().into_bytes())
+        .chain(vec![b"two".to_vec(), b"missing".to_vec(), b"one".to_vec()])
+        .collect();
+    let keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+    let results = cdb.get_many(&keys);
+    assert_eq!(results.len(), keys.len());
+    for (key, result) in keys.iter().zip(results) {
+        match cdb.get(key) {
+            None => assert!(result.is_none()),
+            Some(value) => assert_eq!(result.unwrap().unwrap(), value.unwrap()),
+        }
+    }
+}
+
+#[test]
+fn test_hashed_key() {
+    let one = cdb::HashedKey::new(b"one");
+    let two = cdb::HashedKey::from(b"two".to_vec());
+    assert_eq!(one.key(), b"one");
+    for filename in &["tests/test1.cdb", "tests/test2.cdb"] {
+        let cdb = cdb::CDB::open(filename).unwrap();
+        let values: Vec<Vec<u8>> = cdb.find_hashed(&one).map(|r| r.unwrap()).collect();
+        let expected: Vec<Vec<u8>> = cdb.find(b"one").map(|r| r.unwrap()).collect();
+        assert_eq!(values, expected);
+        assert_eq!(cdb.get_hashed(&two).unwrap().unwrap(), b"Goodbye");
+    }
+}
+
+#[test]
+fn test_locations() {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let cdb = cdb::CDB::open("tests/test1.cdb").unwrap();
+    let read_at = |offset: u32, len: u32| {
+        let mut file = cdb.file();
+        let mut buf = vec![0; len as usize];
+        file.seek(SeekFrom::Start(offset as u64)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        buf
+    };
+
+    let locations: Vec<_> = cdb.find_locations(b"one").map(|r| r.unwrap()).collect();
+    assert_eq!(locations.len(), 2);
+    assert_eq!(
+        read_at(locations[0].key_offset, locations[0].key_len),
+        b"one"
+    );
+    assert_eq!(
+        read_at(locations[0].value_offset, locations[0].value_len),
+        b"Hello"
+    );
+    assert_eq!(
+        read_at(locations[1].value_offset, locations[1].value_len),
+        b", World!"
+    );
+
+    let mut count = 0;
+    for (location, record) in cdb.iter_locations().zip(cdb.iter()) {
+        let (location, (key, value)) = (location.unwrap(), record.unwrap());
+        assert_eq!(read_at(location.key_offset, location.key_len), key);
+        assert_eq!(read_at(location.value_offset, location.value_len), value);
+        count += 1;
+    }
+    assert_eq!(count, 4);
+}
+
+#[test]
+fn test_keys() {
+    let cdb = cdb::CDB::open("tests/test1.cdb").unwrap();
+    let keys: Vec<Vec<u8>> = cdb.keys().map(|r| r.unwrap()).collect();
+    let expected: Vec<Vec<u8>> = cdb.iter().map(|r| r.unwrap().0).collect();
+    assert_eq!(keys, expected);
+
+    let distinct: Vec<Vec<u8>> = cdb.distinct_keys().map(|r| r.unwrap()).collect();
+    assert_eq!(distinct.len(), 3);
+    assert_eq!(distinct[0], b"one");
+    assert_eq!(distinct[1], b"two");
+
+    let cdb = cdb::CDB::open("tests/test2.cdb").unwrap();
+    let mut all: Vec<Vec<u8>> = cdb.keys().map(|r| r.unwrap()).collect();
+    all.sort();
+    all.dedup();
+    let mut distinct: Vec<Vec<u8>> = cdb.distinct_keys().map(|r| r.unwrap()).collect();
+    distinct.sort();
+    assert_eq!(distinct, all);
+}
+
+#[test]
+fn test_groups() {
+    let cdb = cdb::CDB::open("tests/test1.cdb").unwrap();
+    let mut groups = cdb.groups();
+    let (key, values) = groups.next().unwrap().unwrap();
+    assert_eq!(key, b"one");
+    let values: Vec<Vec<u8>> = values.map(|r| r.unwrap()).collect();
+    assert_eq!(values, vec![b"Hello".to_vec(), b", World!".to_vec()]);
+    let (key, values) = groups.next().unwrap().unwrap();
+    assert_eq!(key, b"two");
+    assert_eq!(values.count(), 1);
+    assert!(groups.next().unwrap().is_ok());
+    assert!(groups.next().is_none());
+
+    let cdb = cdb::CDB::open("tests/test2.cdb").unwrap();
+    let map = cdb.to_btree_map().unwrap();
+    assert_eq!(map.len(), cdb.distinct_keys().count());
+    assert_eq!(map[&b"one"[..]].len(), 1000);
+    assert_eq!(map[&b"one"[..]][999], b"1000");
+    assert_eq!(
+        map.values().map(|v| v.len()).sum::<usize>(),
+        cdb.iter().count()
+    );
+}
+
+#[test]
+fn test_cursor() {
+    let cdb = cdb::CDB::open("tests/test2.cdb").unwrap();
+    let all: Vec<_> = cdb.iter().map(|r| r.unwrap()).collect();
+
+    let mut iter = cdb.iter();
+    for _ in 0..500 {
+        iter.next();
+    }
+    let offset = iter.cursor().offset();
+    let rest: Vec<_> = cdb
+        .iter_from(cdb::Cursor::from_offset(offset))
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+    assert_eq!(rest, &all[500..]);
+
+    let mut keys = cdb.keys();
+    keys.next();
+    let rest = cdb.iter_from(keys.cursor()).unwrap();
+    assert_eq!(rest.count(), all.len() - 1);
+
+    let mut end = cdb.iter();
+    while end.next().is_some() {}
+    assert_eq!(cdb.iter_from(end.cursor()).unwrap().count(), 0);
+
+    assert!(cdb.iter_from(cdb::Cursor::from_offset(0)).is_err());
+    assert!(cdb.iter_from(cdb::Cursor::from_offset(offset + 1)).is_err());
+    assert!(cdb.iter_from(cdb::Cursor::from_offset(0xffffffff)).is_err());
+}
+
+/// Check the result of locking memory, allowing for the lock being
+/// refused for lack of privileges or of `RLIMIT_MEMLOCK`, or by the
+/// platform.
+fn check_lock(result: std::io::Result<()>) {
+    use std::io::ErrorKind;
+    match result {
+        Ok(()) => (),
+        Err(ref err)
+            if matches!(
+                err.kind(),
+                ErrorKind::PermissionDenied
+                    | ErrorKind::OutOfMemory
+                    | ErrorKind::WouldBlock
+                    | ErrorKind::Unsupported
+            ) => {}
+        Err(err) => panic!("Locking failed: {}", err),
+    }
+}
+
+#[test]
+fn test_open_options() {
+    let cdb = cdb::OpenOptions::new()
+        .advice(cdb::Advice::Random)
+        .prefetch(true)
+        .open("tests/test2.cdb")
+        .unwrap();
+    assert_eq!(cdb.get(b"two").unwrap().unwrap(), b"Goodbye");
+    cdb.advise(cdb::Advice::Sequential).unwrap();
+    assert_eq!(cdb.iter().count(), 2001);
+    cdb.advise(cdb::Advice::WillNeed).unwrap();
+    cdb.prefetch();
+
+    // Only lock the small test file, to stay within the default
+    // RLIMIT_MEMLOCK.
+    let cdb = cdb::CDB::open("tests/test1.cdb").unwrap();
+    check_lock(cdb.lock(cdb::MemoryLock::Tables));
+    check_lock(cdb.lock(cdb::MemoryLock::File));
+    check_lock(
+        cdb::OpenOptions::new()
+            .lock(cdb::MemoryLock::Tables)
+            .open("tests/test1.cdb")
+            .map(|cdb| assert_eq!(cdb.get(b"one").unwrap().unwrap(), b"Hello")),
+    );
+
+    let err = cdb::OpenOptions::new()
+        .normalizer(cdb::AsciiLowercase)
+        .open("tests/test1.cdb")
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_range_bad_index() {
+    let filename = "tests/read_range_bad_index.cdb";
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    cdb.set_key_index(true).unwrap();
+    for key in &["k0", "k1", "k2", "k3", "k4"] {
+        cdb.add(key.as_bytes(), b"value").unwrap();
+    }
+    cdb.finish().unwrap();
+
+    // Point the index entry for "k3", which finding the bounds of the
+    // whole range never reads, at an empty hash table slot. It reads
+    // as an empty record within the file, but after the records.
+    let mut data = std::fs::read(filename).unwrap();
+    let unpack = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize;
+    let data_end = unpack(&data[0..4]);
+    let count = unpack(&data[data.len() - 8..]);
+    let directory = data.len() - 8 - count * 12;
+    let entry = data[directory..data.len() - 8]
+        .chunks(12)
+        .find(|entry| &entry[0..4] == b"keys")
+        .unwrap();
+    let index = unpack(&entry[4..8]);
+    let empty = (data_end..)
+        .step_by(8)
+        .find(|&pos| data[pos..pos + 8] == [0; 8])
+        .unwrap();
+    data[index + 12..index + 16].copy_from_slice(&(empty as u32).to_le_bytes());
+    std::fs::write(filename, &data).unwrap();
+
+    let cdb = cdb::CDB::open(filename).unwrap();
+    let results: Vec<_> = cdb.range::<std::ops::RangeFull>(..).collect();
+    assert_eq!(results.len(), 5);
+    assert!(results[2].is_ok());
+    assert!(results[3].is_err());
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_par_iter() {
+    use rayon::prelude::*;
+
+    let cdb = cdb::CDB::open("tests/test2.cdb").unwrap();
+    let mut all: Vec<_> = cdb.iter().map(|r| r.unwrap()).collect();
+    let mut par: Vec<_> = cdb.par_iter().map(|r| r.unwrap()).collect();
+    all.sort();
+    par.sort();
+    assert_eq!(par, all);
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_par_iter_bad_table() {
+    use rayon::prelude::*;
+
+    let filename = "tests/read_par_iter_bad_table.cdb";
+    let mut cdb = cdb::CDBWriter::create(filename).unwrap();
+    cdb.add(b"one", b"1").unwrap();
+    cdb.finish().unwrap();
+
+    // Point the record's hash table slot at the empty slot before it,
+    // which reads as an empty record lying within the file.
+    let mut data = std::fs::read(filename).unwrap();
+    let data_end = data[0..4].to_vec();
+    let table = data.len() - 16;
+    data.copy_within(table..table + 8, table + 8);
+    data[table..table + 8].copy_from_slice(&[0; 8]);
+    data[table + 12..table + 16].copy_from_slice(&data_end);
+    std::fs::write(filename, &data).unwrap();
+
+    let cdb = cdb::CDB::open(filename).unwrap();
+    let results: Vec<_> = cdb.par_iter().collect();
+    assert_eq!(results.len(), 1);
+    assert!(results[0].is_err());
+    std::fs::remove_file(filename).unwrap();
+}